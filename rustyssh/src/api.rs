@@ -26,6 +26,12 @@ impl ReadSSH for u32 {
     }
 }
 
+impl ReadSSH for u64 {
+    fn read_ssh<R: std::io::Read>(mut reader: R) -> Result<Self, std::io::Error> {
+        reader.read_u64::<BigEndian>()
+    }
+}
+
 impl ReadSSH for [u8; 16] {
     fn read_ssh<R: std::io::Read>(mut reader: R) -> Result<Self, std::io::Error> {
         let mut buffer = [0u8; 16];
@@ -70,6 +76,21 @@ impl ReadSSH for Vec<String> {
     }
 }
 
+/// Parses a name-based enum (one derived without discriminants) from its SSH name,
+/// e.g. `"ecdh-sha2-nistp256"` into `KeyExchangeMethod::ecdh__sha2__nistp256`.
+pub fn from_ssh_name<T: ReadSSH>(name: &str) -> Result<T, std::io::Error> {
+    let mut bytes = Vec::new();
+    name.to_string().write_ssh(&mut bytes)?;
+    T::read_ssh(bytes.as_slice())
+}
+
+/// The SSH name of a name-based enum, the inverse of `from_ssh_name`.
+pub fn to_ssh_name<T: WriteSSH>(value: &T) -> String {
+    let mut bytes = Vec::new();
+    value.write_ssh(&mut bytes).expect("writing to a Vec cannot fail");
+    String::read_ssh(bytes.as_slice()).expect("name-based enums are written as strings")
+}
+
 pub trait WriteSSH {
     fn write_ssh<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()>;
 }
@@ -80,6 +101,12 @@ impl WriteSSH for u32 {
     }
 }
 
+impl WriteSSH for u64 {
+    fn write_ssh<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&self.to_be_bytes())
+    }
+}
+
 //// Beware. Used to write enum discriminators. Do not overflow :)
 impl WriteSSH for i32 {
     fn write_ssh<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
@@ -149,3 +176,98 @@ impl WriteSSH for Vec<String> {
         writer.write_all(name_list.as_bytes())
     }
 }
+
+/// Multiple precision integer (RFC 4251 §5 `mpint`).
+///
+/// Stored in its canonical two's complement big-endian form: no unnecessary leading
+/// `0x00` or `0xff` bytes, and zero as the empty string.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct MPInt(Vec<u8>);
+
+impl MPInt {
+    /// Builds a non-negative mpint from an unsigned big-endian magnitude, e.g. a
+    /// shared secret or an RSA modulus. Adds the sign byte when the high bit is set.
+    pub fn from_unsigned_bytes(magnitude: &[u8]) -> Self {
+        let start = magnitude.iter().position(|&b| b != 0).unwrap_or(magnitude.len());
+        let magnitude = &magnitude[start..];
+        let mut bytes = Vec::with_capacity(magnitude.len() + 1);
+        if magnitude.first().is_some_and(|&b| b & 0x80 != 0) {
+            bytes.push(0);
+        }
+        bytes.extend_from_slice(magnitude);
+        MPInt(bytes)
+    }
+
+    /// Builds an mpint from an arbitrary two's complement big-endian value, dropping
+    /// redundant sign extension bytes.
+    pub fn from_signed_bytes(bytes: &[u8]) -> Self {
+        let mut start = 0;
+        while start < bytes.len() {
+            let next_high_bit = bytes.get(start + 1).map(|&b| b & 0x80 != 0);
+            match (bytes[start], next_high_bit) {
+                (0x00, None) | (0x00, Some(false)) | (0xff, Some(true)) => start += 1,
+                _ => break,
+            }
+        }
+        MPInt(bytes[start..].to_vec())
+    }
+
+    /// The canonical two's complement encoding, as it appears on the wire after the length.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0.first().is_some_and(|&b| b & 0x80 != 0)
+    }
+
+    /// The unsigned big-endian magnitude without the sign byte, or `None` for negative values.
+    pub fn to_unsigned_bytes(&self) -> Option<Vec<u8>> {
+        if self.is_negative() {
+            return None;
+        }
+        match self.0.first() {
+            Some(0) => Some(self.0[1..].to_vec()),
+            _ => Some(self.0.clone()),
+        }
+    }
+
+    fn is_canonical(bytes: &[u8]) -> bool {
+        match bytes {
+            [] => true,
+            [0x00] => false,
+            [0x00, next, ..] => next & 0x80 != 0,
+            [0xff, next, ..] => next & 0x80 == 0,
+            _ => true,
+        }
+    }
+}
+
+impl From<i64> for MPInt {
+    fn from(value: i64) -> Self {
+        MPInt::from_signed_bytes(&value.to_be_bytes())
+    }
+}
+
+impl ReadSSH for MPInt {
+    fn read_ssh<R: std::io::Read>(reader: R) -> Result<Self, std::io::Error> {
+        let bytes = Vec::<u8>::read_ssh(reader)?;
+        if !MPInt::is_canonical(&bytes) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Non-canonical mpint encoding",
+            ));
+        }
+        Ok(MPInt(bytes))
+    }
+}
+
+impl WriteSSH for MPInt {
+    fn write_ssh<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.0.write_ssh(writer)
+    }
+}
@@ -0,0 +1,222 @@
+use crate::msg::{DisconnectCode, MsgDisconnect};
+use crate::userauth::AuthReply;
+
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Limits on password guessing, after sshd's MaxAuthTries and LoginGraceTime: each
+// connection gets a number of failed attempts and a deadline to log in, failures are
+// answered after a randomized delay, and addresses that keep failing are banned for a while.
+
+/// How much one connection may try before it is disconnected.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct AuthLimits {
+    pub max_auth_tries: u32,         // failed attempts before disconnecting
+    pub login_grace_time: Duration,  // from the connection start to a successful login
+    pub min_failure_delay: Duration, // failures are answered this long after the request at the earliest
+    pub max_failure_delay: Duration, // and this long at the latest, at random in between
+}
+
+impl Default for AuthLimits {
+    fn default() -> Self {
+        AuthLimits {
+            max_auth_tries: 6,
+            login_grace_time: Duration::from_secs(120),
+            min_failure_delay: Duration::from_millis(200),
+            max_failure_delay: Duration::from_millis(400),
+        }
+    }
+}
+
+/// Why a connection is cut off before logging in.
+#[derive(Debug, PartialEq, Clone, thiserror::Error)]
+pub enum AuthLimitExceeded {
+    #[error("Too many authentication failures")]
+    TooManyFailures,
+    #[error("Login grace time exceeded")]
+    GraceTimeExpired,
+    #[error("Illegal user name {0:?}")]
+    IllegalUserName(String),
+}
+
+impl AuthLimitExceeded {
+    /// The SSH_MSG_DISCONNECT to send before closing the connection.
+    pub fn disconnect(&self) -> MsgDisconnect {
+        let code = match self {
+            AuthLimitExceeded::TooManyFailures | AuthLimitExceeded::GraceTimeExpired => {
+                DisconnectCode::NoMoreAuthMethodsAvailable
+            }
+            AuthLimitExceeded::IllegalUserName(_) => DisconnectCode::IllegalUserName,
+        };
+        let description = match self {
+            // Not echoed back, as it may hold anything.
+            AuthLimitExceeded::IllegalUserName(_) => "Illegal user name".to_string(),
+            other => other.to_string(),
+        };
+        MsgDisconnect { code, description, language: String::new() }
+    }
+}
+
+/// Whether `user_name` can name an account: not empty, at most 64 bytes, without control
+/// characters, '/' or ':', and not starting with '-'.
+pub fn valid_user_name(user_name: &str) -> bool {
+    !user_name.is_empty()
+        && user_name.len() <= 64
+        && !user_name.starts_with('-')
+        && !user_name.chars().any(|c| c.is_control() || c == '/' || c == ':')
+}
+
+/// What one userauth reply means for the limits.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AttemptOutcome {
+    /// Not a failure: a success, a partial success, a question or a first "none" request.
+    NotCounted,
+    Failed,
+    /// The last allowed attempt failed; disconnect.
+    TooManyFailures,
+}
+
+/// The authentication attempts of one connection.
+#[derive(Debug)]
+pub struct AuthAttempts {
+    limits: AuthLimits,
+    started: Instant,
+    requests: u32,
+    failures: u32,
+}
+
+impl AuthAttempts {
+    /// Starts the grace time now, at the connection start.
+    pub fn new(limits: AuthLimits) -> Self {
+        AuthAttempts { limits, started: Instant::now(), requests: 0, failures: 0 }
+    }
+
+    pub fn limits(&self) -> &AuthLimits {
+        &self.limits
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Records the reply to a request of `method_name`. Like sshd, a first "none" request,
+    /// which clients send to learn the methods, does not count.
+    pub fn record(&mut self, method_name: &str, reply: &AuthReply) -> AttemptOutcome {
+        self.requests += 1;
+        let failed = matches!(reply, AuthReply::Failure(failure) if !failure.partial_success);
+        if !failed || (self.requests == 1 && method_name == "none") {
+            return AttemptOutcome::NotCounted;
+        }
+        self.failures += 1;
+        if self.failures >= self.limits.max_auth_tries {
+            AttemptOutcome::TooManyFailures
+        } else {
+            AttemptOutcome::Failed
+        }
+    }
+
+    /// The time left to log in.
+    pub fn grace_remaining(&self) -> Duration {
+        self.limits.login_grace_time.saturating_sub(self.started.elapsed())
+    }
+
+    pub fn check_grace_time(&self) -> Result<(), AuthLimitExceeded> {
+        if self.grace_remaining().is_zero() {
+            Err(AuthLimitExceeded::GraceTimeExpired)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// How much longer to wait before answering a failed request that took `elapsed` to
+    /// decide, so that failures take about as long whatever failed.
+    pub fn failure_delay(&self, elapsed: Duration) -> Duration {
+        let (min, max) = (self.limits.min_failure_delay, self.limits.max_failure_delay.max(self.limits.min_failure_delay));
+        let target = if min == max { min } else { rand::thread_rng().gen_range(min..=max) };
+        target.saturating_sub(elapsed)
+    }
+}
+
+/// When addresses are banned.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BanPolicy {
+    pub max_failures: u32, // failures within `window` that ban an address
+    pub window: u64,       // seconds
+    pub ban_time: u64,     // seconds
+}
+
+impl Default for BanPolicy {
+    fn default() -> Self {
+        BanPolicy { max_failures: 10, window: 600, ban_time: 900 }
+    }
+}
+
+#[derive(Debug, Default)]
+struct AddressRecord {
+    failures: Vec<u64>,        // times of recent failures
+    banned_until: Option<u64>, // seconds since the epoch
+}
+
+/// Failed logins per client address, with a temporary ban list. Share one tracker between
+/// connections. Times are seconds since the epoch.
+#[derive(Debug, Default)]
+pub struct FailureTracker {
+    policy: BanPolicy,
+    records: Mutex<HashMap<IpAddr, AddressRecord>>,
+}
+
+impl FailureTracker {
+    pub fn new(policy: BanPolicy) -> Self {
+        FailureTracker { policy, records: Mutex::new(HashMap::new()) }
+    }
+
+    /// Records a failed attempt from `address`. Returns whether the address is now banned.
+    pub fn record_failure(&self, address: IpAddr, now: u64) -> bool {
+        let mut records = self.records.lock().unwrap();
+        let record = records.entry(address.to_canonical()).or_default();
+        record.failures.retain(|&time| time + self.policy.window > now);
+        record.failures.push(now);
+        if record.failures.len() >= self.policy.max_failures as usize {
+            record.failures.clear();
+            record.banned_until = Some(now + self.policy.ban_time);
+        }
+        record.banned_until.is_some_and(|until| until > now)
+    }
+
+    /// Forgets the failures of `address` after a successful login. A ban stays.
+    pub fn record_success(&self, address: IpAddr) {
+        if let Some(record) = self.records.lock().unwrap().get_mut(&address.to_canonical()) {
+            record.failures.clear();
+        }
+    }
+
+    pub fn is_banned(&self, address: IpAddr, now: u64) -> bool {
+        self.records
+            .lock()
+            .unwrap()
+            .get(&address.to_canonical())
+            .and_then(|record| record.banned_until)
+            .is_some_and(|until| until > now)
+    }
+
+    /// The banned addresses and when their bans end, earliest first.
+    pub fn banned(&self, now: u64) -> Vec<(IpAddr, u64)> {
+        let mut banned: Vec<(IpAddr, u64)> = self
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(address, record)| record.banned_until.filter(|&until| until > now).map(|until| (*address, until)))
+            .collect();
+        banned.sort_by_key(|&(address, until)| (until, address));
+        banned
+    }
+
+    /// Lifts the ban on `address` and forgets its failures.
+    pub fn unban(&self, address: IpAddr) {
+        self.records.lock().unwrap().remove(&address.to_canonical());
+    }
+}
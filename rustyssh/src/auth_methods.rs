@@ -0,0 +1,272 @@
+use crate::keys::{PublicKey, SignaturePolicy};
+use crate::userauth::{AuthHandler, AuthResult, Restrictions};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Multi-step authentication like sshd's AuthenticationMethods: a user must complete every
+// method of one chain, in order, before logging in. Each method but the last is answered
+// with a partial success (RFC 4252 §5.1) listing what may come next.
+//
+// Policy files hold one rule per line: a user name, "%group" or "*", then one or more
+// chains of comma-separated methods, e.g. "%admins publickey,keyboard-interactive
+// publickey,password". The first rule matching the user applies; users without one log in
+// with any single method. Blank lines and lines starting with '#' are ignored.
+
+#[derive(Debug, PartialEq, Clone)]
+enum Subject {
+    User(String),
+    Group(String),
+    Anyone,
+}
+
+/// Method chains for users and groups.
+#[derive(Debug, Default, Clone)]
+pub struct AuthenticationMethods {
+    rules: Vec<(Subject, Vec<Vec<String>>)>,
+}
+
+impl AuthenticationMethods {
+    /// Parses a policy file. Chains naming a method twice, or "none", are skipped.
+    pub fn parse(text: &str) -> Self {
+        let rules = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let subject = match fields.next()? {
+                    "*" => Subject::Anyone,
+                    name => match name.strip_prefix('%') {
+                        Some(group) => Subject::Group(group.to_string()),
+                        None => Subject::User(name.to_string()),
+                    },
+                };
+                let chains: Vec<Vec<String>> = fields
+                    .map(|chain| chain.split(',').filter(|method| !method.is_empty()).map(str::to_string).collect())
+                    .filter(|chain: &Vec<String>| {
+                        !chain.is_empty()
+                            && !chain.iter().any(|method| method == "none")
+                            && chain.iter().enumerate().all(|(i, method)| !chain[..i].contains(method))
+                    })
+                    .collect();
+                (!chains.is_empty()).then_some((subject, chains))
+            })
+            .collect();
+        AuthenticationMethods { rules }
+    }
+
+    /// Reads a policy file. A missing file has no rules.
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Adds a rule for `user_name` after the existing ones, for policies not read from a file.
+    pub fn add_user_rule(&mut self, user_name: &str, chains: Vec<Vec<String>>) {
+        self.rules.push((Subject::User(user_name.to_string()), chains));
+    }
+
+    /// The chains `user_name`, a member of `groups`, must complete one of. `None` if no rule
+    /// applies.
+    pub fn chains(&self, user_name: &str, groups: &[String]) -> Option<&[Vec<String>]> {
+        self.rules
+            .iter()
+            .find(|(subject, _)| match subject {
+                Subject::User(name) => name == user_name,
+                Subject::Group(group) => groups.contains(group),
+                Subject::Anyone => true,
+            })
+            .map(|(_, chains)| chains.as_slice())
+    }
+}
+
+/// Group memberships from a file in the /etc/group format, "name:password:gid:user,user".
+/// Only members listed on the group line count; primary groups are not looked up.
+#[derive(Debug, Default, Clone)]
+pub struct Groups {
+    members: HashMap<String, Vec<String>>,
+}
+
+impl Groups {
+    pub fn parse(text: &str) -> Self {
+        let members = text
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split(':').collect();
+                let [name, _password, _gid, members] = fields.as_slice() else {
+                    return None;
+                };
+                let members = members.split(',').map(str::trim).filter(|member| !member.is_empty());
+                Some((name.to_string(), members.map(str::to_string).collect()))
+            })
+            .collect();
+        Groups { members }
+    }
+
+    /// Reads a group file. A missing file has no groups.
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The groups listing `user_name` as a member, sorted.
+    pub fn of(&self, user_name: &str) -> Vec<String> {
+        let mut groups: Vec<String> = self
+            .members
+            .iter()
+            .filter(|(_, members)| members.iter().any(|member| member == user_name))
+            .map(|(group, _)| group.clone())
+            .collect();
+        groups.sort();
+        groups
+    }
+}
+
+/// The methods one user has completed so far.
+#[derive(Debug, Default)]
+struct Progress {
+    user_name: String,
+    completed: Vec<String>,
+    restrictions: Restrictions, // what every completed method allows
+}
+
+/// Enforces `AuthenticationMethods` on top of another handler, which still decides each
+/// method on its own. Like any handler it serves one connection; an attempt for another
+/// user starts over. For users with chains, partial successes of the inner handler count
+/// as failures.
+pub struct MethodChainHandler<H> {
+    pub inner: H,
+    policy: AuthenticationMethods,
+    groups: Groups,
+    progress: Mutex<Progress>,
+}
+
+impl<H: AuthHandler> MethodChainHandler<H> {
+    pub fn new(inner: H, policy: AuthenticationMethods, groups: Groups) -> Self {
+        MethodChainHandler { inner, policy, groups, progress: Mutex::new(Progress::default()) }
+    }
+
+    fn chains(&self, user_name: &str) -> Option<&[Vec<String>]> {
+        self.policy.chains(user_name, &self.groups.of(user_name))
+    }
+
+    /// The methods `user_name` has completed, for the user of the current attempt only.
+    pub fn completed(&self, user_name: &str) -> Vec<String> {
+        let progress = self.progress.lock().unwrap();
+        if progress.user_name == user_name { progress.completed.clone() } else { Vec::new() }
+    }
+
+    /// The methods that continue some chain after `completed`, in policy order.
+    fn next_methods(chains: &[Vec<String>], completed: &[String]) -> Vec<String> {
+        let mut next: Vec<String> = Vec::new();
+        for chain in chains {
+            if let Some(method) = chain.get(completed.len()).filter(|_| chain.starts_with(completed)) {
+                if !next.contains(method) {
+                    next.push(method.clone());
+                }
+            }
+        }
+        next
+    }
+
+    /// Records the inner handler's decision on `method_name` as a step of a chain.
+    fn step(&self, user_name: &str, method_name: &str, result: AuthResult) -> AuthResult {
+        let Some(chains) = self.chains(user_name) else {
+            return result;
+        };
+        let mut progress = self.progress.lock().unwrap();
+        if progress.user_name != user_name {
+            *progress = Progress { user_name: user_name.to_string(), ..Progress::default() };
+        }
+        let granted = match result {
+            AuthResult::Accept => Restrictions::default(),
+            AuthResult::Restricted(restrictions) => restrictions,
+            // Completing a step is up to this handler alone.
+            AuthResult::PartialSuccess => return AuthResult::Reject,
+            other => return other,
+        };
+        if !Self::next_methods(chains, &progress.completed).iter().any(|method| method == method_name) {
+            return AuthResult::Reject;
+        }
+        let Some(restrictions) = progress.restrictions.intersect(&granted) else {
+            return AuthResult::Reject;
+        };
+        progress.completed.push(method_name.to_string());
+        progress.restrictions = restrictions;
+        if chains.iter().any(|chain| *chain == progress.completed) {
+            AuthResult::Restricted(progress.restrictions.clone())
+        } else {
+            AuthResult::PartialSuccess
+        }
+    }
+}
+
+impl<H: AuthHandler> AuthHandler for MethodChainHandler<H> {
+    /// The next methods of the user's chains that the inner handler offers.
+    fn methods(&self, user_name: &str) -> Vec<&'static str> {
+        let offered = self.inner.methods(user_name);
+        match self.chains(user_name) {
+            None => offered,
+            Some(chains) => {
+                let next = Self::next_methods(chains, &self.completed(user_name));
+                offered.into_iter().filter(|method| next.iter().any(|next| next == method)).collect()
+            }
+        }
+    }
+
+    async fn banner(&self, user_name: &str) -> Option<String> {
+        self.inner.banner(user_name).await
+    }
+
+    async fn auth_none(&self, user_name: &str) -> AuthResult {
+        let result = self.inner.auth_none(user_name).await;
+        self.step(user_name, "none", result)
+    }
+
+    async fn auth_password(&self, user_name: &str, password: &str, new_password: Option<&str>) -> AuthResult {
+        let result = self.inner.auth_password(user_name, password, new_password).await;
+        self.step(user_name, "password", result)
+    }
+
+    fn signature_policy(&self) -> SignaturePolicy {
+        self.inner.signature_policy()
+    }
+
+    async fn auth_publickey(&self, user_name: &str, key: &PublicKey) -> AuthResult {
+        let result = self.inner.auth_publickey(user_name, key).await;
+        self.step(user_name, "publickey", result)
+    }
+
+    async fn publickey_acceptable(&self, user_name: &str, key: &PublicKey) -> bool {
+        self.inner.publickey_acceptable(user_name, key).await
+    }
+
+    async fn auth_keyboard_interactive(&self, user_name: &str, submethods: &[String]) -> AuthResult {
+        let result = self.inner.auth_keyboard_interactive(user_name, submethods).await;
+        self.step(user_name, "keyboard-interactive", result)
+    }
+
+    async fn auth_info_response(&self, user_name: &str, responses: &[Vec<String>]) -> AuthResult {
+        let result = self.inner.auth_info_response(user_name, responses).await;
+        self.step(user_name, "keyboard-interactive", result)
+    }
+
+    async fn auth_hostbased(
+        &self,
+        user_name: &str,
+        client_host_name: &str,
+        client_user_name: &str,
+        host_key: &PublicKey,
+    ) -> AuthResult {
+        let result = self.inner.auth_hostbased(user_name, client_host_name, client_user_name, host_key).await;
+        self.step(user_name, "hostbased", result)
+    }
+}
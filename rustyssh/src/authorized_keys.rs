@@ -0,0 +1,324 @@
+use crate::cert::{parse_utc_time, Certificate};
+use crate::keys::{PublicKey, SignaturePolicy};
+use crate::known_hosts::wildcard_match;
+use crate::userauth::Restrictions;
+
+use std::net::IpAddr;
+
+// authorized_keys files as read by sshd (sshd(8), AUTHORIZED_KEYS FILE FORMAT): optional
+// comma-separated options, then a public key. Option values are double-quoted and may
+// contain `\"`. Lines with options we do not know are skipped, as sshd does.
+
+/// The options of an authorized_keys entry.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct KeyOptions {
+    /// The key is a certificate authority trusted to sign user certificates.
+    pub cert_authority: bool,
+    /// from="": patterns the client address must match.
+    pub from: Option<Vec<String>>,
+    /// expiry-time="": seconds since the epoch after which the key is not accepted.
+    pub expiry_time: Option<u64>,
+    /// principals="": names accepted in certificates instead of the user name.
+    pub principals: Option<Vec<String>>,
+    pub restrictions: Restrictions,
+}
+
+impl KeyOptions {
+    /// Applies one option. Options take effect in order, so `restrict,pty` allows a pty.
+    fn apply(&mut self, name: &str, value: Option<String>) -> Option<()> {
+        let restrictions = &mut self.restrictions;
+        match (name.to_ascii_lowercase().as_str(), value) {
+            ("cert-authority", None) => self.cert_authority = true,
+            ("restrict", None) => {
+                *restrictions = Restrictions {
+                    force_command: restrictions.force_command.take(),
+                    permit_open: std::mem::take(&mut restrictions.permit_open),
+                    environment: std::mem::take(&mut restrictions.environment),
+                    ..Restrictions::restricted()
+                }
+            }
+            ("pty", None) => restrictions.pty = true,
+            ("no-pty", None) => restrictions.pty = false,
+            ("port-forwarding", None) => restrictions.port_forwarding = true,
+            ("no-port-forwarding", None) => restrictions.port_forwarding = false,
+            ("agent-forwarding", None) => restrictions.agent_forwarding = true,
+            ("no-agent-forwarding", None) => restrictions.agent_forwarding = false,
+            ("x11-forwarding", None) => restrictions.x11_forwarding = true,
+            ("no-x11-forwarding", None) => restrictions.x11_forwarding = false,
+            ("user-rc", None) => restrictions.user_rc = true,
+            ("no-user-rc", None) => restrictions.user_rc = false,
+            ("command", Some(command)) => restrictions.force_command = Some(command),
+            ("environment", Some(variable)) => {
+                let (name, value) = variable.split_once('=')?;
+                if name.is_empty() {
+                    return None;
+                }
+                restrictions.environment.push((name.to_string(), value.to_string()));
+            }
+            ("permitopen", Some(destination)) => restrictions.permit_open.push(parse_permit_open(&destination)?),
+            ("from", Some(patterns)) => self.from = Some(split_list(&patterns)),
+            ("principals", Some(principals)) => self.principals = Some(split_list(&principals)),
+            ("expiry-time", Some(time)) => {
+                self.expiry_time = Some(parse_utc_time(time.strip_suffix('Z').unwrap_or(&time))?)
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Whether a client connecting from `address` passes the from= option.
+    pub fn allows_from(&self, address: IpAddr) -> bool {
+        self.from.as_ref().is_none_or(|patterns| address_matches(patterns, address))
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expiry_time.is_some_and(|expiry_time| now >= expiry_time)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct AuthorizedKey {
+    pub options: KeyOptions,
+    pub key: PublicKey,
+    pub comment: String,
+    pub line: usize, // 1-based line number in the file
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',').map(str::to_string).collect()
+}
+
+/// "host:port", "[host]:port" for IPv6 addresses, with "*" for any host or port.
+fn parse_permit_open(destination: &str) -> Option<(String, Option<u16>)> {
+    let (host, port) = match destination.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']')?;
+            (host, rest.strip_prefix(':')?)
+        }
+        None => destination.rsplit_once(':')?,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let port = if port == "*" { None } else { Some(port.parse().ok()?) };
+    Some((host.to_string(), port))
+}
+
+/// An option name with its unquoted value, if any.
+type RawOption = (String, Option<String>);
+
+/// Splits a line into its options and the rest, which starts with the key.
+fn split_options(line: &str) -> Option<(Vec<RawOption>, &str)> {
+    let mut options = Vec::new();
+    let mut name = String::new();
+    let mut value: Option<String> = None;
+    let mut chars = line.char_indices();
+    loop {
+        match chars.next() {
+            Some((_, '"')) => {
+                // Quotes are only allowed around a whole value.
+                let value = value.as_mut().filter(|value| value.is_empty())?;
+                loop {
+                    match chars.next()? {
+                        (_, '\\') => match chars.next()? {
+                            (_, '"') => value.push('"'),
+                            (_, c) => {
+                                value.push('\\');
+                                value.push(c);
+                            }
+                        },
+                        (_, '"') => break,
+                        (_, c) => value.push(c),
+                    }
+                }
+            }
+            Some((_, '=')) if value.is_none() => value = Some(String::new()),
+            Some((_, ',')) => options.push((std::mem::take(&mut name), value.take())),
+            Some((index, c)) if c.is_whitespace() => {
+                options.push((name, value));
+                return Some((options, line[index..].trim_start()));
+            }
+            Some((_, c)) => match &mut value {
+                // Values must be quoted.
+                Some(_) => return None,
+                None => name.push(c),
+            },
+            None => return None,
+        }
+    }
+}
+
+/// Parses one line; comments, blank lines, unsupported keys and options give `None`.
+fn parse_entry(line: &str, number: usize) -> Option<AuthorizedKey> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let mut options = KeyOptions::default();
+    let (key, comment) = match PublicKey::from_openssh(line) {
+        Ok(parsed) => parsed,
+        Err(_) => {
+            let (parsed_options, rest) = split_options(line)?;
+            for (name, value) in parsed_options {
+                options.apply(&name, value)?;
+            }
+            PublicKey::from_openssh(rest).ok()?
+        }
+    };
+    // principals= only narrows which certificates an authority may vouch for.
+    if options.principals.is_some() && !options.cert_authority {
+        return None;
+    }
+    Some(AuthorizedKey { options, key, comment, line: number })
+}
+
+/// Whether `address` matches from= patterns: `*` and `?` wildcards on the address or
+/// CIDR blocks such as "10.0.0.0/8", negated by a leading `!`. Host names are never
+/// resolved, as with sshd's UseDNS=no.
+pub fn address_matches(patterns: &[String], address: IpAddr) -> bool {
+    let address = address.to_canonical();
+    let text = address.to_string();
+    let mut matched = false;
+    for pattern in patterns {
+        let pattern = pattern.to_ascii_lowercase();
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern.as_str()),
+        };
+        let matches = match cidr_match(pattern, address) {
+            Some(matches) => matches,
+            None => wildcard_match(pattern, &text),
+        };
+        match (negated, matches) {
+            (true, true) => return false,
+            (false, true) => matched = true,
+            _ => {}
+        }
+    }
+    matched
+}
+
+/// Whether `address` is in a CIDR block; a plain address is a block of one. `None` if
+/// `block` is neither.
+fn cidr_match(block: &str, address: IpAddr) -> Option<bool> {
+    let (network, prefix) = match block.split_once('/') {
+        Some((network, prefix)) => (network.parse::<IpAddr>().ok()?, Some(prefix.parse::<u32>().ok()?)),
+        None => (block.parse::<IpAddr>().ok()?, None),
+    };
+    let (network, address, bits) = match (network, address) {
+        (IpAddr::V4(network), IpAddr::V4(address)) => (u32::from(network) as u128, u32::from(address) as u128, 32),
+        (IpAddr::V6(network), IpAddr::V6(address)) => (u128::from(network), u128::from(address), 128),
+        (IpAddr::V4(_), IpAddr::V6(_)) => return Some(false),
+        (IpAddr::V6(_), IpAddr::V4(_)) => return Some(false),
+    };
+    let prefix = prefix.unwrap_or(bits);
+    if prefix > bits {
+        return None;
+    }
+    let host_bits = bits - prefix;
+    let mask = if host_bits == 128 { 0 } else { !0u128 << host_bits };
+    // A network address with host bits set is a typo, which sshd rejects too.
+    if network & !mask & (u128::MAX >> (128 - bits)) != 0 {
+        return None;
+    }
+    Some(network & mask == address & mask)
+}
+
+/// Whether `address` is in the comma-separated CIDR blocks of a certificate's
+/// source-address option. Any malformed block fails the check.
+pub fn source_address_matches(blocks: &str, address: IpAddr) -> bool {
+    let address = address.to_canonical();
+    let mut matched = false;
+    for block in blocks.split(',') {
+        match cidr_match(block, address) {
+            Some(matches) => matched |= matches,
+            None => return false,
+        }
+    }
+    matched
+}
+
+/// What a user certificate permits: its force-command and permit-* extensions.
+pub fn certificate_restrictions(cert: &Certificate) -> Restrictions {
+    Restrictions {
+        force_command: cert.critical_option("force-command").map(str::to_string),
+        pty: cert.has_extension("permit-pty"),
+        port_forwarding: cert.has_extension("permit-port-forwarding"),
+        agent_forwarding: cert.has_extension("permit-agent-forwarding"),
+        x11_forwarding: cert.has_extension("permit-X11-forwarding"),
+        user_rc: cert.has_extension("permit-user-rc"),
+        permit_open: Vec::new(),
+        environment: Vec::new(),
+    }
+}
+
+/// The entries of an authorized_keys file.
+#[derive(Debug, Default, Clone)]
+pub struct AuthorizedKeys {
+    pub entries: Vec<AuthorizedKey>,
+}
+
+impl AuthorizedKeys {
+    pub fn parse(text: &str) -> Self {
+        let entries = text
+            .lines()
+            .enumerate()
+            .filter_map(|(index, line)| parse_entry(line, index + 1))
+            .collect();
+        AuthorizedKeys { entries }
+    }
+
+    /// Reads an authorized_keys file. A missing file has no entries.
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Decides whether `user_name` connecting from `address` may log in with `key`, and
+    /// with what restrictions. The first entry that accepts the key wins.
+    ///
+    /// Plain keys must be listed themselves. Certificates must be signed by a
+    /// `cert-authority` entry for one of its principals (default: the user name) and come
+    /// from a source-address they allow; the session gets only what both the entry and the
+    /// certificate permit.
+    pub fn authorize(
+        &self,
+        user_name: &str,
+        key: &PublicKey,
+        address: IpAddr,
+        now: u64,
+        policy: &SignaturePolicy,
+    ) -> Option<Restrictions> {
+        for entry in &self.entries {
+            let options = &entry.options;
+            if !options.allows_from(address) || options.is_expired(now) {
+                continue;
+            }
+            match key {
+                PublicKey::Certificate(cert) if options.cert_authority => {
+                    // Like sshd, principals= needs one of its names in the certificate itself.
+                    let require_principal = options.principals.is_some();
+                    let principals = options.principals.clone().unwrap_or_else(|| vec![user_name.to_string()]);
+                    let authorities = std::slice::from_ref(&entry.key);
+                    if cert.check_user(&principals, require_principal, authorities, now, policy).is_err() {
+                        continue;
+                    }
+                    if let Some(blocks) = cert.critical_option("source-address") {
+                        if !source_address_matches(blocks, address) {
+                            continue;
+                        }
+                    }
+                    if let Some(restrictions) = options.restrictions.intersect(&certificate_restrictions(cert)) {
+                        return Some(restrictions);
+                    }
+                }
+                _ if !options.cert_authority && entry.key == *key => return Some(options.restrictions.clone()),
+                _ => {}
+            }
+        }
+        None
+    }
+}
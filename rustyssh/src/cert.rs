@@ -0,0 +1,428 @@
+use crate::api::{ReadSSH, WriteSSH};
+use crate::keys::{KeyError, PrivateKey, PublicKey, SignaturePolicy};
+use ::rustyssh_derive::{ReadSSH, WriteSSH};
+use rand::RngCore;
+
+/// Suffix shared by the key type names of OpenSSH certificates.
+pub const CERT_SUFFIX: &str = "-cert-v01@openssh.com";
+
+/// Extensions ssh-keygen grants to user certificates unless told otherwise.
+pub const DEFAULT_USER_EXTENSIONS: &[&str] = &[
+    "permit-X11-forwarding",
+    "permit-agent-forwarding",
+    "permit-port-forwarding",
+    "permit-pty",
+    "permit-user-rc",
+];
+
+const NONCE_LEN: usize = 32;
+
+/// Whether a certificate identifies a user or a host.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, ReadSSH, WriteSSH)]
+pub enum CertType {
+    User = 1,
+    Host = 2,
+}
+
+/// An OpenSSH certificate (PROTOCOL.certkeys): a public key bound to an identity and
+/// validity period, signed by a certificate authority.
+///
+/// Options are kept as name/value pairs. On the wire a value is nested as a string inside
+/// the option data; an empty value stands for empty data, as used by flag extensions
+/// such as `permit-pty`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Certificate {
+    pub nonce: Vec<u8>,
+    pub key: PublicKey, // the certified key, never itself a certificate
+    pub serial: u64,
+    pub cert_type: CertType,
+    pub key_id: String,
+    pub valid_principals: Vec<String>, // empty means any principal (user certificates only)
+    pub valid_after: u64,              // seconds since the epoch
+    pub valid_before: u64,             // seconds since the epoch, u64::MAX is forever
+    pub critical_options: Vec<(String, String)>,
+    pub extensions: Vec<(String, String)>,
+    pub reserved: Vec<u8>,
+    pub signature_key: PublicKey, // the CA key
+    pub signature: Vec<u8>,       // signature blob over everything above
+}
+
+/// What a certificate authority vouches for when issuing a certificate, see
+/// `Certificate::issue`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CertificateRequest {
+    pub cert_type: CertType,
+    pub key_id: String,
+    pub serial: u64,
+    pub valid_principals: Vec<String>,
+    pub valid_after: u64,
+    pub valid_before: u64,
+    pub critical_options: Vec<(String, String)>,
+    pub extensions: Vec<(String, String)>,
+}
+
+impl CertificateRequest {
+    /// A user certificate valid forever for any principal, with the default extensions.
+    pub fn user(key_id: &str) -> Self {
+        let extensions = DEFAULT_USER_EXTENSIONS.iter().map(|name| (name.to_string(), String::new())).collect();
+        CertificateRequest { cert_type: CertType::User, extensions, ..Self::host(key_id) }
+    }
+
+    /// A host certificate valid forever. Host certificates should name their hosts in
+    /// `valid_principals`, as clients accept none without.
+    pub fn host(key_id: &str) -> Self {
+        CertificateRequest {
+            cert_type: CertType::Host,
+            key_id: key_id.to_string(),
+            serial: 0,
+            valid_principals: Vec::new(),
+            valid_after: 0,
+            valid_before: u64::MAX,
+            critical_options: Vec::new(),
+            extensions: Vec::new(),
+        }
+    }
+}
+
+impl Certificate {
+    /// Certifies `key` with the CA key. Options and extensions are sorted by name, as
+    /// OpenSSH requires, and RSA CAs sign with rsa-sha2-512 like ssh-keygen.
+    pub fn issue(key: &PublicKey, request: &CertificateRequest, ca: &PrivateKey) -> Result<Self, KeyError> {
+        if let PublicKey::Certificate(_) = key {
+            return Err(KeyError::Certificate("Cannot certify a certificate".to_string()));
+        }
+        if request.valid_after >= request.valid_before {
+            return Err(KeyError::Certificate(format!(
+                "{} is never valid: valid_after {} is not before valid_before {}",
+                request.key_id, request.valid_after, request.valid_before
+            )));
+        }
+        let mut nonce = vec![0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let signature_key = ca.public_key();
+        let algorithm = signature_key
+            .signature_algorithms(&SignaturePolicy::default())
+            .into_iter()
+            .next()
+            .ok_or_else(|| KeyError::Unsupported(format!("{} CA keys", signature_key.key_type())))?;
+
+        let mut cert = Certificate {
+            nonce,
+            key: key.clone(),
+            serial: request.serial,
+            cert_type: request.cert_type,
+            key_id: request.key_id.clone(),
+            valid_principals: request.valid_principals.clone(),
+            valid_after: request.valid_after,
+            valid_before: request.valid_before,
+            critical_options: sorted_options(&request.critical_options)?,
+            extensions: sorted_options(&request.extensions)?,
+            reserved: Vec::new(),
+            signature_key,
+            signature: Vec::new(),
+        };
+        cert.signature = ca.sign(&algorithm, &cert.signed_data())?;
+        Ok(cert)
+    }
+
+    /// The certificate key type name, e.g. "ssh-ed25519-cert-v01@openssh.com".
+    pub fn key_type(&self) -> &'static str {
+        match self.key.key_type() {
+            "ssh-rsa" => "ssh-rsa-cert-v01@openssh.com",
+            "ssh-ed25519" => "ssh-ed25519-cert-v01@openssh.com",
+            "ecdsa-sha2-nistp256" => "ecdsa-sha2-nistp256-cert-v01@openssh.com",
+            "ecdsa-sha2-nistp384" => "ecdsa-sha2-nistp384-cert-v01@openssh.com",
+            "ecdsa-sha2-nistp521" => "ecdsa-sha2-nistp521-cert-v01@openssh.com",
+            other => unreachable!("certificates are never nested: {}", other),
+        }
+    }
+
+    /// Reads the certificate fields that follow the key type name.
+    pub(crate) fn read_body<R: std::io::Read>(key_type: &str, mut reader: R) -> Result<Self, std::io::Error> {
+        let plain_type = key_type.strip_suffix(CERT_SUFFIX).unwrap_or(key_type);
+        let nonce = Vec::<u8>::read_ssh(&mut reader)?;
+        let key = PublicKey::read_fields(plain_type, &mut reader)?;
+        let serial = u64::read_ssh(&mut reader)?;
+        let cert_type = CertType::read_ssh(&mut reader)?;
+        let key_id = String::read_ssh(&mut reader)?;
+        let valid_principals = read_string_list(&Vec::<u8>::read_ssh(&mut reader)?)?;
+        let valid_after = u64::read_ssh(&mut reader)?;
+        let valid_before = u64::read_ssh(&mut reader)?;
+        let critical_options = read_options(&Vec::<u8>::read_ssh(&mut reader)?)?;
+        let extensions = read_options(&Vec::<u8>::read_ssh(&mut reader)?)?;
+        let reserved = Vec::<u8>::read_ssh(&mut reader)?;
+        let signature_key = PublicKey::from_blob(&Vec::<u8>::read_ssh(&mut reader)?)?;
+        let signature = Vec::<u8>::read_ssh(&mut reader)?;
+        if let PublicKey::Certificate(_) = signature_key {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Certificate signed by a certificate",
+            ));
+        }
+        Ok(Certificate {
+            nonce,
+            key,
+            serial,
+            cert_type,
+            key_id,
+            valid_principals,
+            valid_after,
+            valid_before,
+            critical_options,
+            extensions,
+            reserved,
+            signature_key,
+            signature,
+        })
+    }
+
+    /// Writes the certificate fields that follow the key type name.
+    pub(crate) fn write_body<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.write_unsigned_body(writer)?;
+        self.signature.write_ssh(writer)
+    }
+
+    fn write_unsigned_body<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.nonce.write_ssh(writer)?;
+        self.key.write_fields(writer)?;
+        self.serial.write_ssh(writer)?;
+        self.cert_type.write_ssh(writer)?;
+        self.key_id.write_ssh(writer)?;
+        write_string_list(&self.valid_principals).write_ssh(writer)?;
+        self.valid_after.write_ssh(writer)?;
+        self.valid_before.write_ssh(writer)?;
+        write_options(&self.critical_options).write_ssh(writer)?;
+        write_options(&self.extensions).write_ssh(writer)?;
+        self.reserved.write_ssh(writer)?;
+        self.signature_key.to_blob().write_ssh(writer)
+    }
+
+    /// The bytes covered by the CA signature: the whole certificate blob up to and
+    /// including the signature key.
+    pub fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.key_type().to_string().write_ssh(&mut data).expect("writing to a Vec cannot fail");
+        self.write_unsigned_body(&mut data).expect("writing to a Vec cannot fail");
+        data
+    }
+
+    pub fn verify_signature(&self, policy: &SignaturePolicy) -> Result<(), KeyError> {
+        self.signature_key.verify(&self.signed_data(), &self.signature, policy)
+    }
+
+    pub fn is_valid_at(&self, now: u64) -> bool {
+        self.valid_after <= now && now < self.valid_before
+    }
+
+    /// Checks a host certificate presented by `hostname` against the trusted certificate
+    /// authorities, as OpenSSH does for `@cert-authority` known_hosts entries.
+    pub fn check_host(
+        &self,
+        hostname: &str,
+        authorities: &[PublicKey],
+        now: u64,
+        policy: &SignaturePolicy,
+    ) -> Result<(), KeyError> {
+        let invalid = |reason: String| Err(KeyError::Certificate(reason));
+        if self.cert_type != CertType::Host {
+            return invalid(format!("{} is not a host certificate", self.key_id));
+        }
+        if !authorities.contains(&self.signature_key) {
+            return invalid(format!("{} is not signed by a trusted authority", self.key_id));
+        }
+        self.verify_signature(policy)?;
+        if !self.is_valid_at(now) {
+            return invalid(format!("{} is not valid at {}", self.key_id, now));
+        }
+        if !self.valid_principals.iter().any(|principal| principal == hostname) {
+            return invalid(format!("{} is not valid for host {}", self.key_id, hostname));
+        }
+        // No critical options are defined for host certificates, so none can be honoured.
+        if let Some((name, _)) = self.critical_options.first() {
+            return invalid(format!("{} has unsupported critical option {}", self.key_id, name));
+        }
+        Ok(())
+    }
+
+    /// Checks a user certificate against the certificate authorities trusted for a login,
+    /// as sshd does for `cert-authority` authorized_keys entries. At least one of
+    /// `principals` must be listed in the certificate; a certificate listing none is valid
+    /// for any principal unless `require_principal`, as for a `principals=` option. Of the
+    /// critical options only force-command and source-address are understood; enforcing them
+    /// is up to the caller.
+    pub fn check_user(
+        &self,
+        principals: &[String],
+        require_principal: bool,
+        authorities: &[PublicKey],
+        now: u64,
+        policy: &SignaturePolicy,
+    ) -> Result<(), KeyError> {
+        let invalid = |reason: String| Err(KeyError::Certificate(reason));
+        if self.cert_type != CertType::User {
+            return invalid(format!("{} is not a user certificate", self.key_id));
+        }
+        if !authorities.contains(&self.signature_key) {
+            return invalid(format!("{} is not signed by a trusted authority", self.key_id));
+        }
+        self.verify_signature(policy)?;
+        if !self.is_valid_at(now) {
+            return invalid(format!("{} is not valid at {}", self.key_id, now));
+        }
+        if (require_principal || !self.valid_principals.is_empty())
+            && !self.valid_principals.iter().any(|principal| principals.contains(principal))
+        {
+            return invalid(format!("{} is not valid for principals {:?}", self.key_id, principals));
+        }
+        if let Some((name, _)) = self
+            .critical_options
+            .iter()
+            .find(|(name, _)| name != "force-command" && name != "source-address")
+        {
+            return invalid(format!("{} has unsupported critical option {}", self.key_id, name));
+        }
+        Ok(())
+    }
+
+    /// The value of a critical option, e.g. "force-command".
+    pub fn critical_option(&self, name: &str) -> Option<&str> {
+        self.critical_options.iter().find(|(option, _)| option == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn has_extension(&self, name: &str) -> bool {
+        self.extensions.iter().any(|(extension, _)| extension == name)
+    }
+}
+
+fn sorted_options(options: &[(String, String)]) -> Result<Vec<(String, String)>, KeyError> {
+    let mut sorted = options.to_vec();
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+    if let Some(pair) = sorted.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(KeyError::Certificate(format!("Duplicate certificate option {}", pair[0].0)));
+    }
+    Ok(sorted)
+}
+
+/// Parses a validity interval as given to `ssh-keygen -V` into (valid_after, valid_before).
+/// "from:to" may start "always" and end "forever"; otherwise each end is a time relative to
+/// `now` such as "-1d" or "+52w1d" (units s, m, h, d, w), an absolute UTC time
+/// YYYYMMDD[HHMM[SS]][Z], or seconds since the epoch in hex ("0x..."). A single time ends
+/// an interval starting now.
+pub fn parse_validity(spec: &str, now: u64) -> Result<(u64, u64), KeyError> {
+    let (from, to) = match spec.split_once(':') {
+        Some(("always", to)) => (Some(0), to),
+        Some((from, to)) => (parse_validity_time(from, now), to),
+        None => (Some(now), spec),
+    };
+    let to = if to == "forever" { Some(u64::MAX) } else { parse_validity_time(to, now) };
+    match (from, to) {
+        (Some(from), Some(to)) if from < to => Ok((from, to)),
+        _ => Err(KeyError::Certificate(format!("Invalid validity interval {}", spec))),
+    }
+}
+
+/// One end of a validity interval, other than "always" or "forever".
+fn parse_validity_time(time: &str, now: u64) -> Option<u64> {
+    if let Some(hex) = time.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16).ok();
+    }
+    if let Some(offset) = time.strip_prefix('+') {
+        return parse_interval(offset).and_then(|offset| now.checked_add(offset));
+    }
+    if let Some(offset) = time.strip_prefix('-') {
+        return parse_interval(offset).and_then(|offset| now.checked_sub(offset));
+    }
+    parse_utc_time(time.strip_suffix('Z').unwrap_or(time))
+}
+
+/// An interval such as "52w1d" in seconds, as in sshd_config time formats.
+fn parse_interval(interval: &str) -> Option<u64> {
+    let mut total: u64 = 0;
+    let mut digits = String::new();
+    for c in interval.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        let value: u64 = digits.parse().ok()?;
+        total = total.checked_add(value.checked_mul(unit)?)?;
+        digits.clear();
+    }
+    if !digits.is_empty() {
+        total = total.checked_add(digits.parse().ok()?)?;
+    } else if interval.is_empty() {
+        return None;
+    }
+    Some(total)
+}
+
+/// Seconds since the epoch of a UTC time YYYYMMDD[HHMM[SS]].
+pub(crate) fn parse_utc_time(time: &str) -> Option<u64> {
+    if !matches!(time.len(), 8 | 12 | 14) || !time.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let field = |range: std::ops::Range<usize>| time.get(range).map_or(0, |digits| digits.parse::<u64>().unwrap());
+    let (year, month, day) = (field(0..4), field(4..6), field(6..8));
+    let (hour, minute, second) = (field(8..10), field(10..12), field(12..14));
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 || year < 1970 {
+        return None;
+    }
+    // Days since 1970-01-01 of a proleptic Gregorian date, counting years from March so
+    // that the leap day comes last.
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let (era, year_of_era) = (y / 400, y % 400);
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146097 + day_of_era).checked_sub(719468)?;
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+fn read_string_list(bytes: &[u8]) -> Result<Vec<String>, std::io::Error> {
+    let mut reader = std::io::Cursor::new(bytes);
+    let mut list = Vec::new();
+    while (reader.position() as usize) < bytes.len() {
+        list.push(String::read_ssh(&mut reader)?);
+    }
+    Ok(list)
+}
+
+fn write_string_list(list: &[String]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for item in list {
+        item.write_ssh(&mut bytes).expect("writing to a Vec cannot fail");
+    }
+    bytes
+}
+
+fn read_options(bytes: &[u8]) -> Result<Vec<(String, String)>, std::io::Error> {
+    let mut reader = std::io::Cursor::new(bytes);
+    let mut options = Vec::new();
+    while (reader.position() as usize) < bytes.len() {
+        let name = String::read_ssh(&mut reader)?;
+        let data = Vec::<u8>::read_ssh(&mut reader)?;
+        let value = if data.is_empty() { String::new() } else { String::read_ssh(data.as_slice())? };
+        options.push((name, value));
+    }
+    Ok(options)
+}
+
+fn write_options(options: &[(String, String)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (name, value) in options {
+        name.write_ssh(&mut bytes).expect("writing to a Vec cannot fail");
+        let mut data = Vec::new();
+        if !value.is_empty() {
+            value.write_ssh(&mut data).expect("writing to a Vec cannot fail");
+        }
+        data.write_ssh(&mut bytes).expect("writing to a Vec cannot fail");
+    }
+    bytes
+}
@@ -1,4 +1,4 @@
-use std::io::{Cursor, Read, Write}; // Cursor re-added
+use std::io::{Cursor, Read, Write};
 use std::net::TcpStream;
 use std::process;
 use std::time::Duration;
@@ -6,456 +6,621 @@ use std::time::Duration;
 use rand::RngCore; // Added for random cookie
 
 mod api;
+mod cert;
+mod fingerprint;
+mod hostkeys;
+mod kex;
+mod keys;
+mod known_hosts;
 mod msg;
+mod transport;
+mod userauth;
 
 use pretty_hex::*;
 
-use crate::api::WriteSSH;
+use crate::api::{from_ssh_name, to_ssh_name, WriteSSH};
+use crate::kex::{
+    kex_algorithm_by_name, negotiate_algorithm, ExchangeHashInput, KeyLengths, KexState, SessionId, SUPPORTED_KEX_ALGORITHMS,
+};
+use crate::fingerprint::{display_type, FingerprintHash};
+use crate::keys::{PublicKey, SignaturePolicy};
+use crate::known_hosts::{host_lookup_name, HostKeyPolicy, HostKeyStatus, HostKeyVerifier, KnownHostsVerifier};
 use crate::msg::*;
+use crate::transport::{PacketCipher, PacketReader, PacketWriter, SUPPORTED_CIPHERS, SUPPORTED_MACS};
+
+const CLIENT_VERSION: &str = "SSH-2.0-rustyssh_0.1.0";
+
+/// Command line: `client_dev [host] [port] [--user <name>] [--password <password>] [--new-password <password>]
+/// [--known-hosts <path>] [--strict-host-key-checking] [--update-host-keys] [--visual-host-key]
+/// [--hostbased-key <path> [--client-host <name>]] [--no-banner]`.
+struct Options {
+    host: String,
+    port: u16,
+    user: String,
+    password: Option<String>,
+    new_password: Option<String>, // for a server asking to change the password
+    hostbased_key: Option<std::path::PathBuf>, // the client host's private key
+    client_host: String,                       // our name as the server should resolve it
+    show_banner: bool,
+    known_hosts: std::path::PathBuf,
+    host_key_policy: HostKeyPolicy,
+    update_host_keys: bool,
+    visual_host_key: bool,
+}
 
-fn main() -> std::io::Result<()> {
-    // Attempt to establish a TCP connection
-    match TcpStream::connect("localhost:22") {
-        Ok(mut stream) => {
-            println!("Successfully connected to localhost:22");
-
-            let protocol_version = "SSH-2.0-rustyssh_0.1.0\r\n";
-            println!("Sending protocol version: {}", protocol_version.trim_end()); // Log without CRLF for cleaner output
-            if let Err(e) = stream.write_all(protocol_version.as_bytes()) {
-                eprintln!("Failed to send protocol version: {}", e);
-                process::exit(1);
-            }
+impl Options {
+    fn from_args() -> Self {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let value_of = |flag: &str| {
+            args.windows(2).find(|pair| pair[0] == flag).map(|pair| pair[1].clone())
+        };
+        let flags_with_values = ["--user", "--password", "--new-password", "--known-hosts", "--hostbased-key", "--client-host"];
+        let positional: Vec<&String> = args
+            .iter()
+            .enumerate()
+            .filter(|(i, arg)| {
+                !arg.starts_with("--") && (*i == 0 || !flags_with_values.contains(&args[i - 1].as_str()))
+            })
+            .map(|(_, arg)| arg)
+            .collect();
+
+        let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+        Options {
+            host: positional.first().map_or("localhost".to_string(), |host| host.to_string()),
+            port: positional.get(1).and_then(|port| port.parse().ok()).unwrap_or(22),
+            user: value_of("--user").unwrap_or_else(|| std::env::var("USER").unwrap_or_else(|_| "admin".to_string())),
+            password: value_of("--password"),
+            new_password: value_of("--new-password"),
+            hostbased_key: value_of("--hostbased-key").map(Into::into),
+            client_host: value_of("--client-host").unwrap_or_else(|| "localhost".to_string()),
+            known_hosts: value_of("--known-hosts")
+                .map_or_else(|| std::path::Path::new(&home).join(".ssh/known_hosts"), Into::into),
+            host_key_policy: if args.iter().any(|arg| arg == "--strict-host-key-checking") {
+                HostKeyPolicy::Strict
+            } else {
+                HostKeyPolicy::AcceptNew
+            },
+            update_host_keys: args.iter().any(|arg| arg == "--update-host-keys"),
+            visual_host_key: args.iter().any(|arg| arg == "--visual-host-key"),
+            show_banner: !args.iter().any(|arg| arg == "--no-banner"),
+        }
+    }
+}
+
+/// A blocking connection speaking the binary packet protocol.
+struct Connection {
+    stream: TcpStream,
+    read_buffer: Vec<u8>,
+    reader: PacketReader,
+    writer: PacketWriter,
+    context: MessageContext,
+    on_banner: Box<dyn FnMut(&MsgUserauthBanner)>, // called with each SSH_MSG_USERAUTH_BANNER
+}
+
+impl Connection {
+    fn send(&mut self, msg: &(impl WriteSSH + std::fmt::Debug)) -> std::io::Result<()> {
+        let mut payload = Vec::new();
+        msg.write_ssh(&mut payload)?;
+        println!("Sending {:?}", msg);
+        self.send_payload(&payload)
+    }
+
+    /// Sends a message assembled by hand, such as a request with method-specific fields.
+    fn send_payload(&mut self, payload: &[u8]) -> std::io::Result<()> {
+        let packet = self.writer.seal(payload);
+        self.stream.write_all(&packet)
+    }
 
-            // Set a read timeout
-            if let Err(e) = stream.set_read_timeout(Some(Duration::new(5, 0))) {
-                eprintln!("Failed to set read timeout: {}", e);
-                process::exit(1);
+    fn receive_payload(&mut self) -> std::io::Result<Vec<u8>> {
+        loop {
+            if let Some(payload) = self.reader.open(&mut self.read_buffer)? {
+                return Ok(payload);
+            }
+            let mut chunk = [0u8; 4096];
+            let n = self.stream.read(&mut chunk)?;
+            if n == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Server closed the connection",
+                ));
             }
+            self.read_buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
 
-            // Read the server's protocol version string
-            let mut buffer = [0u8; 256];
-            match stream.read(&mut buffer) {
-                Ok(n) => {
-                    if n == 0 {
-                        eprintln!("Server closed connection prematurely while reading protocol string.");
-                        process::exit(1);
-                    }
-                    let server_response = String::from_utf8_lossy(&buffer[..n]);
-                    println!("Received from server: {}", server_response.trim());
-
-                    // Parse the server's protocol string
-                    // It should be something like "SSH-2.0-OpenSSH_8.9p1 Ubuntu-3ubuntu0.1\r\n"
-                    // We need to extract "SSH-2.0-OpenSSH_8.9p1 Ubuntu-3ubuntu0.1"
-                    let mut parts = server_response.trim_end().splitn(2, "\r\n");
-                    let server_protocol_string = parts.next().unwrap_or("").trim();
-
-
-                    if server_protocol_string.starts_with("SSH-2.0-") {
-                        println!("Parsed server protocol version: {}", server_protocol_string);
-                    } else {
-                        eprintln!(
-                            "Invalid server protocol string format. Expected 'SSH-2.0-...', got: {}",
-                            server_response.trim()
-                        );
-                        process::exit(1);
-                    }
-                }
-                Err(e) => {
-                    if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut {
-                        eprintln!("Timeout waiting for server protocol string.");
-                    } else {
-                        eprintln!("Failed to read server protocol string: {}", e);
-                    }
-                    process::exit(1);
+    /// Receives the next message, skipping SSH_MSG_IGNORE and SSH_MSG_DEBUG and handing
+    /// banners to `on_banner`. SSH_MSG_DISCONNECT is turned into an error.
+    fn receive(&mut self) -> std::io::Result<SSHMsg> {
+        loop {
+            let payload = self.receive_payload()?;
+            match read_next_message_in(Cursor::new(&payload), &self.context)? {
+                SSHMsg::Ignore(_) => {}
+                SSHMsg::Debug(debug) => println!("Server debug message: {}", debug.message),
+                SSHMsg::UserauthBanner(banner) => (self.on_banner)(&banner),
+                SSHMsg::Disconnect(disconnect) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionAborted,
+                        format!("Server disconnected ({:?}): {}", disconnect.code, disconnect.description),
+                    ))
                 }
+                msg => return Ok(msg),
             }
+        }
+    }
 
-            // Construct MsgKexInit
-            let mut cookie = [0u8; 16];
-            rand::thread_rng().fill_bytes(&mut cookie);
-
-            let kex_init_payload = MsgKexInit {
-                cookie,
-                kex_algorithms: vec![
-                    "ecdh-sha2-nistp256".to_string(),
-                    "diffie-hellman-group-exchange-sha256".to_string(),
-                    "diffie-hellman-group14-sha256".to_string(),
-                ],
-                server_host_key_algorithms: vec![
-                    "ssh-ed25519".to_string(),
-                    "rsa-sha2-512".to_string(),
-                    "rsa-sha2-256".to_string(),
-                    "ssh-rsa".to_string(),
-                ],
-                encryption_algorithms_client_to_server: vec![
-                    "aes128-ctr".to_string(),
-                    "aes192-ctr".to_string(),
-                    "aes256-ctr".to_string(),
-                ],
-                encryption_algorithms_server_to_client: vec![
-                    "aes128-ctr".to_string(),
-                    "aes192-ctr".to_string(),
-                    "aes256-ctr".to_string(),
-                ],
-                mac_algorithms_client_to_server: vec![
-                    "hmac-sha2-256".to_string(),
-                    "hmac-sha1".to_string(),
-                ],
-                mac_algorithms_server_to_client: vec![
-                    "hmac-sha2-256".to_string(),
-                    "hmac-sha1".to_string(),
-                ],
-                compression_algorithms_client_to_server: vec!["none".to_string()],
-                compression_algorithms_server_to_client: vec!["none".to_string()],
-                languages_client_to_server: Vec::new(),
-                languages_server_to_client: Vec::new(),
-                kex_first_packet_follows: false,
-                reserved: 0, // As per RFC 4253, this should be 0
-            };
-
-            // Serialize into a byte array
-            let mut kex_init_bytes = Vec::new();
-            kex_init_payload.write_ssh(&mut kex_init_bytes)?;
-
-            println!("Sending KEXINIT: {:?}", kex_init_payload);
-            println!("KEXINIT Bytes: {:?}", kex_init_bytes.hex_dump());
-
-            // Send KEXINIT message
-            if let Err(e) = stream.write_all(&kex_init_bytes) {
-                eprintln!("Failed to send KEXINIT message: {}", e);
-                process::exit(1);
+    /// Receives a message and keeps its raw payload, which the exchange hash needs for KEXINIT.
+    fn receive_with_payload(&mut self) -> std::io::Result<(SSHMsg, Vec<u8>)> {
+        loop {
+            let payload = self.receive_payload()?;
+            match read_next_message_in(Cursor::new(&payload), &self.context)? {
+                SSHMsg::Ignore(_) | SSHMsg::Debug(_) => {}
+                msg => return Ok((msg, payload)),
             }
+        }
+    }
+}
 
-            // For now, we are not processing the server's KEXINIT response
-            // The old deserialization test code is removed.
+fn unexpected(expected: &str, msg: &SSHMsg) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Expected {}, got {:?}", expected, msg),
+    )
+}
 
-            // Set read timeout again for server's KEXINIT
-            if let Err(e) = stream.set_read_timeout(Some(Duration::new(5, 0))) {
-                eprintln!("Failed to set read timeout for server KEXINIT: {}", e);
-                process::exit(1);
+/// Sends our identification string and reads the server's. Lines before the server's
+/// version are allowed (RFC 4253 §4.2); bytes after it already belong to the first packet.
+fn exchange_versions(stream: &mut TcpStream) -> std::io::Result<(String, Vec<u8>)> {
+    println!("Sending protocol version: {}", CLIENT_VERSION);
+    stream.write_all(format!("{}\r\n", CLIENT_VERSION).as_bytes())?;
+
+    let mut buffer = Vec::new();
+    loop {
+        if let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line).trim_end().to_string();
+            if line.starts_with("SSH-2.0-") {
+                println!("Parsed server protocol version: {}", line);
+                return Ok((line, buffer));
             }
+            println!("Server banner line: {}", line);
+            continue;
+        }
+        let mut chunk = [0u8; 256];
+        let n = stream.read(&mut chunk)?;
+        if n == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Server closed connection prematurely while reading protocol string.",
+            ));
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+}
 
-            // Read the server's KEXINIT response
-            let mut kex_buffer = [0u8; 4096]; // Buffer for KEXINIT
-            match stream.read(&mut kex_buffer) {
-                Ok(bytes_read) => {
-                    if bytes_read == 0 {
-                        eprintln!("Server closed connection while waiting for KEXINIT.");
-                        process::exit(1);
-                    }
-                    println!(
-                        "Received KEXINIT raw bytes ({} bytes): {:?}",
-                        bytes_read,
-                        (&kex_buffer[..bytes_read]).hex_dump()
-                    );
-
-                    if bytes_read < 5 {
-                        eprintln!("Error: Received KEXINIT packet too short to contain length and padding info ({} bytes).", bytes_read);
-                        process::exit(1);
-                    }
-
-                    let packet_length = u32::from_be_bytes(kex_buffer[0..4].try_into().expect("Slice for packet_length is wrong size"));
-                    let padding_length = kex_buffer[4];
-
-                    // payload_in_packet_offset is the offset of the SSH message type code from the start of kex_buffer
-                    let payload_in_packet_offset = 5;
-
-                    // msg_payload_length is the length of the SSH message itself (e.g. KEXINIT data)
-                    // It does not include the packet_length field, padding_length field, or the random padding itself.
-                    if packet_length < (padding_length as u32 + 1) {
-                        eprintln!("Error: Invalid packet structure. packet_length ({}) is too small to accommodate padding_length ({}) + 1 byte for msg type.", packet_length, padding_length);
-                        process::exit(1);
-                    }
-                    let msg_payload_length = packet_length as usize - padding_length as usize - 1;
-
-                    // total_expected_bytes_for_this_packet is 4 (packet_length field) + its value (packet_length)
-                    let total_expected_bytes_for_this_packet = 4 + packet_length as usize;
-
-                    if bytes_read < total_expected_bytes_for_this_packet {
-                        eprintln!(
-                            "Error: Incomplete packet. Expected {} bytes for the full SSH packet based on its length field, but only received {} bytes.",
-                            total_expected_bytes_for_this_packet, bytes_read
-                        );
-                        // This could be a legitimate partial read, but for now, we'll treat it as an error.
-                        // A more robust client might buffer and retry reading.
-                        process::exit(1);
-                    }
-
-                    // The cursor should operate on the SSH message payload part of the buffer
-                    let ssh_message_payload_slice = &kex_buffer[payload_in_packet_offset .. payload_in_packet_offset + msg_payload_length];
-
-                    let mut cursor = Cursor::new(ssh_message_payload_slice);
-                    match read_next_message(&mut cursor) {
-                        Ok(SSHMsg::KexInit(server_kex_init)) => {
-                            println!("Successfully parsed server KEXINIT: {:?}", server_kex_init);
-
-                            // Check if read_next_message consumed the entire payload it was given
-                            if cursor.position() < msg_payload_length as u64 {
-                                eprintln!(
-                                    "Error: Did not consume entire KEXINIT message payload. Payload length: {}, Parsed: {}. Unparsed part: {:?}",
-                                    msg_payload_length,
-                                    cursor.position(),
-                                    (&ssh_message_payload_slice[cursor.position() as usize..]).hex_dump()
-                                );
-                                process::exit(1);
-                            }
-
-                            // Check if there are any extra unparsed bytes beyond the current SSH packet in the buffer
-                            if bytes_read > total_expected_bytes_for_this_packet {
-                                eprintln!(
-                                    "Error: Trailing bytes ({}) received after the current SSH packet. Total read: {}, Expected for packet: {}. Trailing data: {:?}",
-                                    bytes_read - total_expected_bytes_for_this_packet,
-                                    bytes_read,
-                                    total_expected_bytes_for_this_packet,
-                                    (&kex_buffer[total_expected_bytes_for_this_packet..bytes_read]).hex_dump()
-                                );
-                                process::exit(1);
-                            }
-
-                            // Algorithm Negotiation
-                            println!("\n--- Algorithm Negotiation ---");
-
-                            fn find_first_common(client_list: &[String], server_list: &[String]) -> Option<String> {
-                                client_list.iter().find(|algo| server_list.contains(algo)).cloned()
-                            }
-
-                            let chosen_kex_algo = find_first_common(
-                                &kex_init_payload.kex_algorithms,
-                                &server_kex_init.kex_algorithms,
-                            );
-                            match chosen_kex_algo {
-                                Some(algo) if algo == "ecdh-sha2-nistp256" => {
-                                    println!("Chosen KEX algorithm: {}", algo);
-                                    // Store for later: let chosen_kex_algo = algo;
-                                }
-                                Some(algo) => {
-                                    eprintln!("Unsupported KEX algorithm chosen: {}. We only support 'ecdh-sha2-nistp256'.", algo);
-                                    process::exit(1);
-                                }
-                                None => {
-                                    eprintln!("No common KEX algorithm found.");
-                                    eprintln!("Client offered: {:?}", kex_init_payload.kex_algorithms);
-                                    eprintln!("Server offered: {:?}", server_kex_init.kex_algorithms);
-                                    process::exit(1);
-                                }
-                            }
-
-                            let chosen_host_key_algo = find_first_common(
-                                &kex_init_payload.server_host_key_algorithms,
-                                &server_kex_init.server_host_key_algorithms,
-                            );
-                            match chosen_host_key_algo {
-                                Some(algo) => println!("Chosen server host key algorithm: {}", algo),
-                                None => {
-                                    eprintln!("No common server host key algorithm found.");
-                                    process::exit(1);
-                                }
-                            }
-
-                            let chosen_enc_c2s = find_first_common(
-                                &kex_init_payload.encryption_algorithms_client_to_server,
-                                &server_kex_init.encryption_algorithms_client_to_server,
-                            );
-                            match chosen_enc_c2s {
-                                Some(algo) if algo == "aes128-ctr" => {
-                                     println!("Chosen client-to-server encryption algorithm: {}", algo);
-                                }
-                                Some(algo) => {
-                                    eprintln!("Unsupported C2S encryption algorithm chosen: {}. We only support 'aes128-ctr'.", algo);
-                                    process::exit(1);
-                                }
-                                None => {
-                                    eprintln!("No common client-to-server encryption algorithm found.");
-                                    process::exit(1);
-                                }
-                            }
-
-                            let chosen_enc_s2c = find_first_common(
-                                &kex_init_payload.encryption_algorithms_server_to_client,
-                                &server_kex_init.encryption_algorithms_server_to_client,
-                            );
-                             match chosen_enc_s2c {
-                                Some(algo) if algo == "aes128-ctr" => {
-                                     println!("Chosen server-to-client encryption algorithm: {}", algo);
-                                }
-                                Some(algo) => {
-                                    eprintln!("Unsupported S2C encryption algorithm chosen: {}. We only support 'aes128-ctr'.", algo);
-                                    process::exit(1);
-                                }
-                                None => {
-                                    eprintln!("No common server-to-client encryption algorithm found.");
-                                    process::exit(1);
-                                }
-                            }
-
-                            let chosen_mac_c2s = find_first_common(
-                                &kex_init_payload.mac_algorithms_client_to_server,
-                                &server_kex_init.mac_algorithms_client_to_server,
-                            );
-                            match chosen_mac_c2s {
-                                Some(algo) if algo == "hmac-sha2-256" => {
-                                     println!("Chosen client-to-server MAC algorithm: {}", algo);
-                                }
-                                Some(algo) => {
-                                    eprintln!("Unsupported C2S MAC algorithm chosen: {}. We only support 'hmac-sha2-256'.", algo);
-                                    process::exit(1);
-                                }
-                                None => {
-                                    eprintln!("No common client-to-server MAC algorithm found.");
-                                    process::exit(1);
-                                }
-                            }
-
-                            let chosen_mac_s2c = find_first_common(
-                                &kex_init_payload.mac_algorithms_server_to_client,
-                                &server_kex_init.mac_algorithms_server_to_client,
-                            );
-                            match chosen_mac_s2c {
-                                Some(algo) if algo == "hmac-sha2-256" => {
-                                     println!("Chosen server-to-client MAC algorithm: {}", algo);
-                                }
-                                 Some(algo) => {
-                                    eprintln!("Unsupported S2C MAC algorithm chosen: {}. We only support 'hmac-sha2-256'.", algo);
-                                    process::exit(1);
-                                }
-                                None => {
-                                    eprintln!("No common server-to-client MAC algorithm found.");
-                                    process::exit(1);
-                                }
-                            }
-
-                            // Compression (assuming "none" is the only one we offer/accept for now)
-                            if kex_init_payload.compression_algorithms_client_to_server.get(0).map(String::as_str) == Some("none") &&
-                               server_kex_init.compression_algorithms_client_to_server.get(0).map(String::as_str) == Some("none") {
-                                println!("Chosen client-to-server compression: none");
-                            } else {
-                                eprintln!("Failed to agree on 'none' for C2S compression.");
-                                process::exit(1);
-                            }
-                             if kex_init_payload.compression_algorithms_server_to_client.get(0).map(String::as_str) == Some("none") &&
-                               server_kex_init.compression_algorithms_server_to_client.get(0).map(String::as_str) == Some("none") {
-                                println!("Chosen server-to-client compression: none");
-                            } else {
-                                eprintln!("Failed to agree on 'none' for S2C compression.");
-                                process::exit(1);
-                            }
-                            println!("--- Algorithm Negotiation Complete ---");
-
-                            // Part 2: ECDH Key Generation (if ecdh-sha2-nistp256 is chosen)
-                            // We've already confirmed chosen_kex_algo is Some("ecdh-sha2-nistp256")
-                            // so we can proceed.
-
-                            use openssl::bn::BigNumContext;
-                            use openssl::ec::{EcGroup, EcKey, PointConversionForm};
-                            use openssl::nid::Nid;
-                            use rand::Rng; // For random padding
-
-                            println!("\n--- ECDH Key Exchange (ecdh-sha2-nistp256) ---");
-
-                            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
-                                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to create EC group: {}", e)))?;
-                            let ec_key = EcKey::generate(&group)
-                                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to generate EC key: {}", e)))?;
-
-                            let mut bn_ctx = BigNumContext::new()
-                                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to create BigNumContext: {}", e)))?;
-
-                            let q_c_bytes = ec_key.public_key().to_bytes(
-                                &group,
-                                PointConversionForm::UNCOMPRESSED,
-                                &mut bn_ctx,
-                            ).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to get public key bytes: {}", e)))?;
-
-                            println!("Client ephemeral public key (Q_C) generated ({} bytes): {:?}", q_c_bytes.len(), q_c_bytes.hex_dump());
-
-                            // Part 3: Construct and Send MsgKexEcdhInit
-                            let kex_ecdh_init_msg = MsgKexECDHInit { q_c: q_c_bytes };
-
-                            let mut kex_ecdh_init_payload_bytes = Vec::new();
-                            // Manually write MAGIC first, then the struct for KexEcdhInit
-                            // because write_ssh on the struct itself doesn't add the MAGIC.
-                            // Correction: The derive ReadSSH/WriteSSH for enums like SSHMsg handles the magic.
-                            // When calling write_ssh on a specific message struct, we are writing the payload *after* magic.
-                            // The `read_next_message` reads magic then calls specific `Msg::read_ssh`.
-                            // So, for sending, we serialize the struct, then prepend magic for the payload.
-                            // No, this is also not quite right. write_ssh for the struct is for its fields.
-                            // The actual message construction should be:
-                            // 1. Create struct (e.g. kex_ecdh_init_msg)
-                            // 2. Create a Vec, write MAGIC to it.
-                            // 3. Call kex_ecdh_init_msg.write_ssh(&mut Vec) to append fields.
-                            // This Vec is then the "SSH Message Payload" for packetization.
-
-                            // kex_ecdh_init_payload_bytes.push(MsgKexECDHInit::MAGIC); // Removed: derived write_ssh should handle MAGIC
-                            kex_ecdh_init_msg.write_ssh(&mut kex_ecdh_init_payload_bytes)?;
-
-                            println!("Serialized MsgKexEcdhInit SSH message (incl. MAGIC, {} bytes): {:?}", kex_ecdh_init_payload_bytes.len(), kex_ecdh_init_payload_bytes.hex_dump());
-
-                            // Packet construction
-                            let block_size = 8; // Cipher block size (or 8 if no cipher yet)
-                            let payload_len = kex_ecdh_init_payload_bytes.len();
-
-                            // Calculate padding length: must be at least 4 bytes
-                            // total length of (padding_length_byte + payload + padding) must be multiple of block_size
-                            let mut padding_length = block_size - (1 + payload_len) % block_size;
-                            if padding_length < 4 {
-                                padding_length += block_size;
-                            }
-                            if padding_length >= 256 { // Should not happen with typical payloads and block_size
-                                eprintln!("Calculated excessive padding: {}", padding_length);
-                                process::exit(1);
-                            }
-
-
-                            let packet_len_field = (1 + payload_len + padding_length) as u32; // padding_length_byte + payload + random_padding
-
-                            let mut final_packet_bytes = Vec::new();
-                            final_packet_bytes.extend_from_slice(&packet_len_field.to_be_bytes());
-                            final_packet_bytes.push(padding_length as u8);
-                            final_packet_bytes.extend_from_slice(&kex_ecdh_init_payload_bytes);
-
-                            let mut padding_bytes = vec![0u8; padding_length];
-                            rand::thread_rng().fill(&mut padding_bytes[..]); // Removed ?
-                            final_packet_bytes.extend_from_slice(&padding_bytes);
-
-                            println!("Sending SSH_MSG_KEX_ECDH_INIT ({} bytes packet): {:?}", final_packet_bytes.len(), final_packet_bytes.hex_dump());
-                            println!("  Packet Length Field: {}", packet_len_field);
-                            println!("  Padding Length Field: {}", padding_length);
-                            println!("  Payload (MsgKexEcdhInit with MAGIC): {} bytes", payload_len);
-                            println!("  Padding: {} bytes", padding_length);
-
-
-                            if let Err(e) = stream.write_all(&final_packet_bytes) {
-                                eprintln!("Failed to send SSH_MSG_KEX_ECDH_INIT: {}", e);
-                                process::exit(1);
-                            }
-                            println!("SSH_MSG_KEX_ECDH_INIT sent.");
-
-                            // Next step would be to read MsgKexEcdhReply...
-                        }
-                        Ok(other_msg) => {
-                            eprintln!("Unexpected SSH message received instead of KEXINIT (parsed from payload): {:?}", other_msg);
-                            process::exit(1);
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to parse server KEXINIT message: {}", e);
-                            process::exit(1);
-                        }
-                    }
-                }
-                Err(e) => {
-                    if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut {
-                        eprintln!("Timeout waiting for server KEXINIT message.");
-                    } else {
-                        eprintln!("Failed to read server KEXINIT message: {}", e);
-                    }
-                    process::exit(1);
-                }
+fn client_kex_init() -> MsgKexInit {
+    let mut cookie = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut cookie);
+    let names = |list: &[&str]| list.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+
+    MsgKexInit {
+        cookie,
+        kex_algorithms: names(SUPPORTED_KEX_ALGORITHMS),
+        // Legacy ssh-rsa (SHA-1) is only offered with SignaturePolicy::allow_ssh_rsa_sha1
+        server_host_key_algorithms: SignaturePolicy::default()
+            .algorithms()
+            .iter()
+            .map(to_ssh_name)
+            .collect(),
+        encryption_algorithms_client_to_server: names(SUPPORTED_CIPHERS),
+        encryption_algorithms_server_to_client: names(SUPPORTED_CIPHERS),
+        mac_algorithms_client_to_server: names(SUPPORTED_MACS),
+        mac_algorithms_server_to_client: names(SUPPORTED_MACS),
+        compression_algorithms_client_to_server: vec!["none".to_string()],
+        compression_algorithms_server_to_client: vec!["none".to_string()],
+        languages_client_to_server: Vec::new(),
+        languages_server_to_client: Vec::new(),
+        kex_first_packet_follows: false,
+        reserved: 0, // As per RFC 4253, this should be 0
+    }
+}
+
+fn negotiate(name: &str, client_list: &[String], server_list: &[String]) -> std::io::Result<String> {
+    match negotiate_algorithm(client_list, server_list) {
+        Some(algo) => {
+            println!("Chosen {}: {}", name, algo);
+            Ok(algo)
+        }
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("No common {}. Client offered {:?}, server offered {:?}", name, client_list, server_list),
+        )),
+    }
+}
+
+/// Runs the key exchange up to and including NEWKEYS in both directions, after which
+/// all packets are protected with the negotiated cipher and MAC.
+fn key_exchange(
+    conn: &mut Connection,
+    server_version: &str,
+    options: &Options,
+    verifier: &mut KnownHostsVerifier,
+    kex_state: &mut KexState,
+) -> std::io::Result<PublicKeyAlgorithm> {
+    let kex_init = client_kex_init();
+    let mut kex_init_payload = Vec::new();
+    kex_init.write_ssh(&mut kex_init_payload)?;
+    println!("KEXINIT Bytes: {:?}", kex_init_payload.hex_dump());
+    conn.send(&kex_init)?;
+
+    let (server_kex_init_payload, kex_name, host_key_algorithm) = match conn.receive_with_payload()? {
+        (SSHMsg::KexInit(server_kex_init), payload) => {
+            println!("Successfully parsed server KEXINIT: {:?}", server_kex_init);
+            println!("\n--- Algorithm Negotiation ---");
+            let kex_name = negotiate("KEX algorithm", &kex_init.kex_algorithms, &server_kex_init.kex_algorithms)?;
+            conn.context.kex_method = from_ssh_name(&kex_name).ok();
+            let host_key_algorithm = negotiate(
+                "server host key algorithm",
+                &kex_init.server_host_key_algorithms,
+                &server_kex_init.server_host_key_algorithms,
+            )?;
+            negotiate(
+                "client-to-server encryption algorithm",
+                &kex_init.encryption_algorithms_client_to_server,
+                &server_kex_init.encryption_algorithms_client_to_server,
+            )?;
+            negotiate(
+                "server-to-client encryption algorithm",
+                &kex_init.encryption_algorithms_server_to_client,
+                &server_kex_init.encryption_algorithms_server_to_client,
+            )?;
+            negotiate(
+                "client-to-server MAC algorithm",
+                &kex_init.mac_algorithms_client_to_server,
+                &server_kex_init.mac_algorithms_client_to_server,
+            )?;
+            negotiate(
+                "server-to-client MAC algorithm",
+                &kex_init.mac_algorithms_server_to_client,
+                &server_kex_init.mac_algorithms_server_to_client,
+            )?;
+            negotiate(
+                "client-to-server compression",
+                &kex_init.compression_algorithms_client_to_server,
+                &server_kex_init.compression_algorithms_client_to_server,
+            )?;
+            negotiate(
+                "server-to-client compression",
+                &kex_init.compression_algorithms_server_to_client,
+                &server_kex_init.compression_algorithms_server_to_client,
+            )?;
+            println!("--- Algorithm Negotiation Complete ---");
+            (payload, kex_name, from_ssh_name::<PublicKeyAlgorithm>(&host_key_algorithm)?)
+        }
+        (other, _) => return Err(unexpected("KEXINIT", &other)),
+    };
+
+    println!("\n--- ECDH Key Exchange ({}) ---", kex_name);
+    let mut kex = kex_algorithm_by_name(&kex_name).expect("negotiated from SUPPORTED_KEX_ALGORITHMS");
+    let q_c = kex.client_start()?;
+    println!("Client ephemeral public key (Q_C) generated ({} bytes): {:?}", q_c.len(), q_c.hex_dump());
+    conn.send(&MsgKexECDHInit { q_c })?;
+
+    let reply = match conn.receive()? {
+        SSHMsg::KexECDHReply(reply) => reply,
+        other => return Err(unexpected("KEX_ECDH_REPLY", &other)),
+    };
+    let hash_input = ExchangeHashInput {
+        client_version: CLIENT_VERSION.as_bytes().to_vec(),
+        server_version: server_version.as_bytes().to_vec(),
+        client_kex_init: kex_init_payload,
+        server_kex_init: server_kex_init_payload,
+    };
+    let output = kex.client_finish(&hash_input, &reply.k_s, &reply.q_s)?;
+    let host_key = output
+        .verify_host_signature(&host_key_algorithm, &reply.signature, &SignaturePolicy::default())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    println!("Server host key signature verified: {} {}", host_key.key_type(), host_key.fingerprint());
+    show_host_key(options, verifier, &host_key);
+    if let Err(e) = verifier.verify(&options.host, options.port, &host_key) {
+        let disconnect = MsgDisconnect {
+            code: DisconnectCode::HostKeyNotVerifiable,
+            description: "Host key verification failed".to_string(),
+            language: String::new(),
+        };
+        let _ = conn.send(&disconnect);
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, e));
+    }
+
+    let session_id = kex_state.record_exchange(&output).clone();
+    // aes128-ctr and hmac-sha2-256
+    let key_lengths = KeyLengths { iv: 16, enc: 16, mac: 32 };
+    let session_keys = output.derive_session_keys(kex.hash(), &session_id, &key_lengths);
+
+    conn.send(&MsgNewKeys {})?;
+    conn.writer.set_cipher(PacketCipher::client_to_server(&session_keys)?);
+    match conn.receive()? {
+        SSHMsg::NewKeys(_) => conn.reader.set_cipher(PacketCipher::server_to_client(&session_keys)?),
+        other => return Err(unexpected("NEWKEYS", &other)),
+    }
+    conn.context.kex_method = None;
+    println!("--- Key Exchange Complete, packets are now encrypted ---");
+    Ok(host_key_algorithm)
+}
+
+/// Shows the host key the way OpenSSH asks about it: fingerprints for a host seen for the
+/// first time, and the randomart with --visual-host-key.
+fn show_host_key(options: &Options, verifier: &KnownHostsVerifier, host_key: &PublicKey) {
+    let key_type = display_type(host_key);
+    let status = verifier.known_hosts().check(&options.host, options.port, host_key);
+    if status == HostKeyStatus::Unknown && !matches!(host_key, PublicKey::Certificate(_)) {
+        let name = host_lookup_name(&options.host, options.port);
+        println!("The authenticity of host '{}' can't be established.", name);
+        println!("{} key fingerprint is {}.", key_type, host_key.fingerprint());
+        println!("{} key fingerprint is {}.", key_type, host_key.fingerprint_with(FingerprintHash::Md5));
+    }
+    if options.visual_host_key {
+        println!("Host key fingerprint is {}", host_key.fingerprint());
+        println!("{}", host_key.randomart(FingerprintHash::Sha256));
+    }
+}
+
+/// Answers keyboard-interactive prompts: "Password" prompts with `--password` if given,
+/// everything else from standard input.
+fn prompt_on_terminal(request: &MsgUserauthInfoRequest, password: Option<&str>) -> Vec<String> {
+    for line in [&request.name, &request.instruction] {
+        if !line.is_empty() {
+            println!("{}", line);
+        }
+    }
+    request
+        .prompts
+        .iter()
+        .map(|prompt| match password {
+            Some(password) if prompt.prompt.to_ascii_lowercase().starts_with("password") => password.to_string(),
+            _ => {
+                // Without a terminal library the answer is echoed either way.
+                print!("{}", prompt.prompt);
+                std::io::stdout().flush().ok();
+                let mut answer = String::new();
+                std::io::stdin().read_line(&mut answer).ok();
+                answer.trim_end_matches(['\r', '\n']).to_string()
+            }
+        })
+        .collect()
+}
+
+/// Runs a keyboard-interactive exchange (RFC 4256), answering each round with `prompt`.
+fn keyboard_interactive(
+    conn: &mut Connection,
+    request: MsgUserauthRequest,
+    prompt: &mut impl FnMut(&MsgUserauthInfoRequest) -> Vec<String>,
+) -> std::io::Result<bool> {
+    conn.context.auth_method = Some(AuthenticationMethod::keyboard__interactive);
+    let mut payload = Vec::new();
+    request.write_ssh(&mut payload)?;
+    String::new().write_ssh(&mut payload)?; // language tag
+    String::new().write_ssh(&mut payload)?; // submethods
+    println!("Sending keyboard-interactive authentication request for {}", request.user_name);
+    conn.send_payload(&payload)?;
+    loop {
+        match conn.receive()? {
+            SSHMsg::UserauthSuccess(_) => return Ok(true),
+            SSHMsg::UserauthFailure(_) => return Ok(false),
+            SSHMsg::UserauthInfoRequest(info_request) => {
+                let response = userauth::answer_info_request(&info_request, prompt)?;
+                // Sent by hand to keep the answers out of the log
+                let mut payload = Vec::new();
+                response.write_ssh(&mut payload)?;
+                println!("Sending {} keyboard-interactive answers", response.responses.len());
+                conn.send_payload(&payload)?;
+            }
+            other => return Err(unexpected("USERAUTH_INFO_REQUEST, USERAUTH_SUCCESS or USERAUTH_FAILURE", &other)),
+        }
+    }
+}
+
+/// Signs a hostbased request (RFC 4252 §9) with the client host key at `key_path`, on behalf
+/// of the local user.
+fn hostbased(
+    conn: &mut Connection,
+    request: MsgUserauthRequest,
+    session_id: &SessionId,
+    key_path: &std::path::Path,
+    client_host: &str,
+) -> std::io::Result<bool> {
+    let invalid = |e: keys::KeyError| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    let (host_key, _comment) = keys::load_private_key(key_path, None).map_err(invalid)?;
+    let algorithm = host_key.public_key().signature_algorithms(&SignaturePolicy::default())[0].clone();
+    let client_user = std::env::var("USER").unwrap_or_else(|_| request.user_name.clone());
+    let fields = userauth::sign_hostbased_request(session_id, &request, &host_key, &algorithm, client_host, &client_user)
+        .map_err(invalid)?;
+    conn.context.auth_method = Some(AuthenticationMethod::hostbased);
+    let mut payload = Vec::new();
+    request.write_ssh(&mut payload)?;
+    fields.write_fields(&mut payload)?;
+    println!(
+        "Sending hostbased authentication request for {} as {}@{} with {}",
+        request.user_name,
+        client_user,
+        client_host,
+        to_ssh_name(&algorithm)
+    );
+    conn.send_payload(&payload)?;
+    match conn.receive()? {
+        SSHMsg::UserauthSuccess(_) => Ok(true),
+        SSHMsg::UserauthFailure(_) => Ok(false),
+        other => Err(unexpected("USERAUTH_SUCCESS or USERAUTH_FAILURE", &other)),
+    }
+}
+
+/// Tries "none", then "hostbased" if given a host key, "keyboard-interactive" and then, if
+/// given and allowed, "password" authentication, changing the password if the server asks.
+fn authenticate(conn: &mut Connection, options: &Options, session_id: &SessionId) -> std::io::Result<bool> {
+    conn.send(&MsgServiceRequest { service_name: "ssh-userauth".to_string() })?;
+    match conn.receive()? {
+        SSHMsg::ServiceAccept(accept) if accept.service_name == "ssh-userauth" => {
+            println!("Server accepted the ssh-userauth service");
+        }
+        other => return Err(unexpected("SERVICE_ACCEPT for ssh-userauth", &other)),
+    }
+
+    let request = |method_name: &str| MsgUserauthRequest {
+        user_name: options.user.clone(),
+        service_name: "ssh-connection".to_string(),
+        method_name: method_name.to_string(),
+    };
+    conn.send(&request("none"))?;
+    let methods = match conn.receive()? {
+        SSHMsg::UserauthSuccess(_) => return Ok(true),
+        SSHMsg::UserauthFailure(failure) => failure.authentications_that_can_continue,
+        other => return Err(unexpected("USERAUTH_FAILURE", &other)),
+    };
+    println!("Authentications that can continue: {}", methods.join(","));
+
+    if let Some(key_path) = &options.hostbased_key {
+        if methods.iter().any(|method| method == "hostbased")
+            && hostbased(conn, request("hostbased"), session_id, key_path, &options.client_host)?
+        {
+            return Ok(true);
+        }
+    }
+
+    if methods.iter().any(|method| method == "keyboard-interactive") {
+        let password = options.password.as_deref();
+        if keyboard_interactive(conn, request("keyboard-interactive"), &mut |info_request| {
+            prompt_on_terminal(info_request, password)
+        })? {
+            return Ok(true);
+        }
+    }
+    let Some(password) = &options.password else {
+        return Ok(false);
+    };
+    if !methods.iter().any(|method| method == "password") {
+        return Ok(false);
+    }
+    conn.context.auth_method = Some(AuthenticationMethod::password);
+    let mut new_password = options.new_password.clone();
+    let mut fields = userauth::AuthRequest::Password { password: password.clone(), new_password: None };
+    loop {
+        // Sent by hand to keep the passwords out of the log
+        let mut payload = Vec::new();
+        request("password").write_ssh(&mut payload)?;
+        fields.write_fields(&mut payload)?;
+        println!("Sending password authentication request for {}", options.user);
+        conn.send_payload(&payload)?;
+        let change = match conn.receive()? {
+            SSHMsg::UserauthSuccess(_) => return Ok(true),
+            SSHMsg::UserauthFailure(_) => return Ok(false),
+            SSHMsg::UserauthPasswdChangeReq(change) => change,
+            other => return Err(unexpected("USERAUTH_SUCCESS, USERAUTH_FAILURE or USERAUTH_PASSWD_CHANGEREQ", &other)),
+        };
+        print!("{}", change.prompt);
+        // --new-password is offered once; after that, or without it, ask on the terminal.
+        let chosen = match new_password.take() {
+            Some(chosen) => chosen,
+            None if options.new_password.is_some() => return Ok(false),
+            None => {
+                print!("New password: ");
+                std::io::stdout().flush().ok();
+                let mut answer = String::new();
+                std::io::stdin().read_line(&mut answer)?;
+                answer.trim_end_matches(['\r', '\n']).to_string()
             }
+        };
+        if chosen.is_empty() {
+            return Ok(false);
+        }
+        fields = userauth::password_change_request(password, &chosen);
+    }
+}
+
+/// Waits briefly for the server's hostkeys-00@openssh.com announcement and records the
+/// announced keys in known_hosts once the server has proven it holds the new ones.
+fn update_known_host_keys(conn: &mut Connection, options: &Options, kex_state: &KexState) -> std::io::Result<()> {
+    conn.stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let announced = match conn.receive() {
+        Ok(SSHMsg::GlobalRequest(MsgGlobalRequest { request: GlobalRequest::HostKeys(blobs), .. })) => blobs,
+        Ok(other) => {
+            println!("No host key announcement, got {:?}", other);
+            return Ok(());
         }
-        Err(e) => {
-            eprintln!("Failed to connect to localhost:22: {}", e);
-            process::exit(1);
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+            println!("No host key announcement received.");
+            return Ok(());
         }
+        Err(e) => return Err(e),
+    };
+
+    let known = known_hosts::KnownHosts::load(&options.known_hosts)?.host_keys(&options.host, options.port);
+    let new_keys = hostkeys::unknown_host_keys(&announced, &known);
+    if !new_keys.is_empty() {
+        println!("Asking the server to prove {} new host keys", new_keys.len());
+        conn.send(&hostkeys::prove_request(&new_keys))?;
+        let response = match conn.receive()? {
+            SSHMsg::RequestSuccess(response) => response,
+            other => return Err(unexpected("REQUEST_SUCCESS", &other)),
+        };
+        let session_id = kex_state.session_id().expect("key exchange completed");
+        hostkeys::verify_host_key_proofs(session_id, &new_keys, &response, &SignaturePolicy::default())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    }
+
+    let keys: Vec<PublicKey> = announced
+        .iter()
+        .filter_map(|blob| PublicKey::from_blob(blob).ok())
+        .filter(|key| !matches!(key, PublicKey::Certificate(_)))
+        .collect();
+    let name = host_lookup_name(&options.host, options.port);
+    if known_hosts::update_host_keys(&options.known_hosts, &name, &keys)? {
+        println!("Updated {} with {} host keys for {}", options.known_hosts.display(), keys.len(), name);
+    } else {
+        println!("Not updating {}: {} is listed with other names or a wildcard", options.known_hosts.display(), name);
     }
     Ok(())
 }
+
+fn run(options: &Options) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect((options.host.as_str(), options.port))?;
+    println!("Successfully connected to {}:{}", options.host, options.port);
+    stream.set_read_timeout(Some(Duration::new(5, 0)))?;
+
+    let (server_version, read_buffer) = exchange_versions(&mut stream)?;
+    let mut conn = Connection {
+        stream,
+        read_buffer,
+        reader: PacketReader::default(),
+        writer: PacketWriter::default(),
+        context: MessageContext::default(),
+        on_banner: if options.show_banner {
+            Box::new(|banner: &MsgUserauthBanner| print!("{}", userauth::sanitize_banner(&banner.message)))
+        } else {
+            Box::new(|_: &MsgUserauthBanner| {})
+        },
+    };
+
+    let mut verifier = KnownHostsVerifier::load(&options.known_hosts, options.host_key_policy)?;
+    let mut kex_state = KexState::default();
+    key_exchange(&mut conn, &server_version, options, &mut verifier, &mut kex_state)?;
+
+    let session_id = kex_state.session_id().expect("key exchange completed").clone();
+    let authenticated = authenticate(&mut conn, options, &session_id)?;
+    println!("Authenticated: {}", authenticated);
+    if authenticated && options.update_host_keys {
+        update_known_host_keys(&mut conn, options, &kex_state)?;
+    }
+
+    conn.send(&MsgDisconnect {
+        code: DisconnectCode::ByApplication,
+        description: "Done".to_string(),
+        language: String::new(),
+    })
+}
+
+fn main() {
+    let options = Options::from_args();
+    if let Err(e) = run(&options) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::keys::PublicKey;
+
+use base64::engine::general_purpose::STANDARD_NO_PAD as BASE64_NO_PAD;
+use base64::Engine;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+/// Hash used for a key fingerprint, as selected with `ssh-keygen -E`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum FingerprintHash {
+    #[default]
+    Sha256,
+    /// The legacy format shown by OpenSSH before 6.8.
+    Md5,
+}
+
+impl FingerprintHash {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FingerprintHash::Sha256 => "SHA256",
+            FingerprintHash::Md5 => "MD5",
+        }
+    }
+}
+
+/// The digest of a public key blob. Displays as OpenSSH does:
+/// "SHA256:" and unpadded base64, or "MD5:" and colon-separated hex.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Fingerprint {
+    pub hash: FingerprintHash,
+    pub digest: Vec<u8>,
+}
+
+impl Fingerprint {
+    /// Fingerprints any key blob, including blobs of key types this crate cannot parse.
+    pub fn of_blob(blob: &[u8], hash: FingerprintHash) -> Self {
+        let digest = match hash {
+            FingerprintHash::Sha256 => Sha256::digest(blob).to_vec(),
+            FingerprintHash::Md5 => Md5::digest(blob).to_vec(),
+        };
+        Fingerprint { hash, digest }
+    }
+}
+
+impl std::fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", self.hash.name())?;
+        match self.hash {
+            FingerprintHash::Sha256 => write!(f, "{}", BASE64_NO_PAD.encode(&self.digest)),
+            FingerprintHash::Md5 => {
+                let hex: Vec<String> = self.digest.iter().map(|b| format!("{:02x}", b)).collect();
+                write!(f, "{}", hex.join(":"))
+            }
+        }
+    }
+}
+
+const FIELD_WIDTH: usize = 17;
+const FIELD_HEIGHT: usize = 9;
+// Symbols by visit count; the last two mark the start and end of the walk.
+const AUGMENTATION: &[u8] = b" .o+=*BOX@%&#/^SE";
+
+/// The key type as shown in `ssh-keygen -l` output, e.g. "ED25519" or "ECDSA-CERT".
+pub fn display_type(key: &PublicKey) -> String {
+    match key {
+        PublicKey::Ed25519(_) => "ED25519".to_string(),
+        PublicKey::Rsa(_) => "RSA".to_string(),
+        PublicKey::Ecdsa { .. } => "ECDSA".to_string(),
+        PublicKey::Certificate(cert) => format!("{}-CERT", display_type(&cert.key)),
+    }
+}
+
+/// Renders a fingerprint as OpenSSH's "randomart" (the drunken bishop walk, as in
+/// `ssh-keygen -lv` and VisualHostKey). The box is framed with the key type and size on
+/// top and the hash name at the bottom, and has no trailing newline.
+pub fn randomart(key: &PublicKey, fingerprint: &Fingerprint) -> String {
+    let mut field = [[0u8; FIELD_HEIGHT]; FIELD_WIDTH];
+    let max = AUGMENTATION.len() - 1;
+    let (mut x, mut y) = (FIELD_WIDTH / 2, FIELD_HEIGHT / 2);
+
+    for &byte in &fingerprint.digest {
+        let mut input = byte;
+        for _ in 0..4 {
+            x = if input & 0x1 != 0 { (x + 1).min(FIELD_WIDTH - 1) } else { x.saturating_sub(1) };
+            y = if input & 0x2 != 0 { (y + 1).min(FIELD_HEIGHT - 1) } else { y.saturating_sub(1) };
+            if (field[x][y] as usize) < max - 2 {
+                field[x][y] += 1;
+            }
+            input >>= 2;
+        }
+    }
+    field[FIELD_WIDTH / 2][FIELD_HEIGHT / 2] = (max - 1) as u8;
+    field[x][y] = max as u8;
+
+    // Like OpenSSH's snprintf into a FIELD_WIDTH + 1 byte buffer: the size is dropped when
+    // the title overflows it, and whatever is left is cut to fit.
+    let mut title = format!("[{} {}]", display_type(key), key.bits());
+    if title.len() > FIELD_WIDTH + 1 {
+        title = format!("[{}]", display_type(key));
+    }
+    title.truncate(FIELD_WIDTH);
+    let footer = format!("[{}]", fingerprint.hash.name());
+
+    let mut art = framed_line(&title);
+    for y in 0..FIELD_HEIGHT {
+        art.push('|');
+        art.extend((0..FIELD_WIDTH).map(|x| AUGMENTATION[(field[x][y] as usize).min(max)] as char));
+        art.push_str("|\n");
+    }
+    art.push_str(framed_line(&footer).trim_end());
+    art
+}
+
+/// A border line with `label` centered as OpenSSH does, leaning left.
+fn framed_line(label: &str) -> String {
+    let left = (FIELD_WIDTH - label.len()) / 2;
+    let right = FIELD_WIDTH - label.len() - left;
+    format!("+{}{}{}+\n", "-".repeat(left), label, "-".repeat(right))
+}
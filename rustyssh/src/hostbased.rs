@@ -0,0 +1,174 @@
+use crate::keys::{PublicKey, SignaturePolicy};
+use crate::known_hosts::{HostKeyError, HostKeyPolicy, HostKeyVerifier, KnownHosts, KnownHostsVerifier};
+
+use std::net::IpAddr;
+
+// Server-side checks of "hostbased" requests (RFC 4252 §9) once their signature has been
+// verified, as sshd does them: the client host name must belong to the connecting address,
+// the host key must be known for that name, and the client user must be trusted to log in
+// as the requested user.
+//
+// shosts.equiv files list one host per line, optionally followed by a user name:
+// "client.example.com" trusts every user of that host as the user of the same name,
+// "client.example.com alice" trusts alice as anyone. A leading '-' on either field denies
+// instead. The first matching line decides. As in sshd, wild entries, a bare "+" for any
+// host or user, are ignored.
+
+/// One line of a shosts.equiv file.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ShostsEntry {
+    pub host: String,
+    pub host_negated: bool,   // "-host": users of the host are never trusted
+    pub user: Option<String>, // None: the client user must have the same name
+    pub user_negated: bool,   // "host -user": that user is never trusted
+    pub line: usize,          // 1-based line number in the file
+}
+
+impl ShostsEntry {
+    fn matches_host(&self, host: &str) -> bool {
+        self.host.eq_ignore_ascii_case(host)
+    }
+
+    fn matches_user(&self, client_user_name: &str, user_name: &str) -> bool {
+        match &self.user {
+            Some(user) => user == client_user_name,
+            None => client_user_name == user_name,
+        }
+    }
+}
+
+fn parse_entry(line: &str, number: usize) -> Option<ShostsEntry> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let mut fields = line.split_whitespace();
+    let host = fields.next()?;
+    let (host_negated, host) = match host.strip_prefix('-') {
+        Some(host) => (true, host),
+        None => (false, host),
+    };
+    // Wild names ("+", or "-" alone) are skipped, and netgroups ("+@group") are not
+    // supported; such lines never match.
+    let skipped = |name: &str| name.is_empty() || name == "+" || name.starts_with('@') || name.starts_with("+@");
+    if skipped(host) {
+        return None;
+    }
+    let (user_negated, user) = match fields.next() {
+        Some(user) => match user.strip_prefix('-') {
+            Some(user) => (true, Some(user.to_string())),
+            None => (false, Some(user.to_string())),
+        },
+        None => (false, None),
+    };
+    if user.as_deref().is_some_and(skipped) {
+        return None;
+    }
+    Some(ShostsEntry { host: host.to_string(), host_negated, user, user_negated, line: number })
+}
+
+/// The entries of a shosts.equiv file.
+#[derive(Debug, Default, Clone)]
+pub struct ShostsEquiv {
+    pub entries: Vec<ShostsEntry>,
+}
+
+impl ShostsEquiv {
+    pub fn parse(text: &str) -> Self {
+        let entries = text
+            .lines()
+            .enumerate()
+            .filter_map(|(index, line)| parse_entry(line, index + 1))
+            .collect();
+        ShostsEquiv { entries }
+    }
+
+    /// Reads a shosts.equiv file. A missing file has no entries.
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether `client_user_name` on `client_host_name` may log in as `user_name`.
+    pub fn allows(&self, client_host_name: &str, client_user_name: &str, user_name: &str) -> bool {
+        let client_host_name = normalize_host_name(client_host_name);
+        for entry in &self.entries {
+            // Like sshd, a '-' on either field denies only once both the host and the user match.
+            if entry.matches_host(client_host_name) && entry.matches_user(client_user_name, user_name) {
+                return !(entry.host_negated || entry.user_negated);
+            }
+        }
+        false
+    }
+}
+
+/// Host names in requests are fully qualified and may end in a dot, as OpenSSH sends them.
+fn normalize_host_name(name: &str) -> &str {
+    name.strip_suffix('.').unwrap_or(name)
+}
+
+/// Whether the client host name of a request belongs to the connecting address: it is
+/// that address, or it resolves to it.
+pub async fn host_name_matches(client_host_name: &str, address: IpAddr) -> bool {
+    let name = normalize_host_name(client_host_name);
+    let address = address.to_canonical();
+    if let Ok(literal) = name.parse::<IpAddr>() {
+        return literal.to_canonical() == address;
+    }
+    match tokio::net::lookup_host((name, 0)).await {
+        Ok(mut resolved) => resolved.any(|socket_address| socket_address.ip().to_canonical() == address),
+        Err(_) => false,
+    }
+}
+
+/// Why a hostbased request with a valid signature was not accepted.
+#[derive(Debug, thiserror::Error)]
+pub enum HostbasedError {
+    #[error("Client host {host} does not resolve to {address}")]
+    AddressMismatch { host: String, address: IpAddr },
+    #[error("{client_user}@{host} may not log in as {user}")]
+    NotEquivalent { host: String, client_user: String, user: String },
+    #[error(transparent)]
+    HostKey(#[from] HostKeyError),
+}
+
+/// The checks of hostbased requests: shosts.equiv for users, known_hosts for host keys.
+/// Host certificates are accepted from an `@cert-authority` for the client host.
+#[derive(Debug, Default, Clone)]
+pub struct HostbasedPolicy {
+    pub shosts_equiv: ShostsEquiv,
+    pub known_hosts: KnownHosts,
+    pub signature_policy: SignaturePolicy,
+}
+
+impl HostbasedPolicy {
+    /// Decides whether `client_user_name` on the client host, which signed the request
+    /// with `host_key` and connected from `address`, may log in as `user_name`.
+    pub async fn check(
+        &self,
+        user_name: &str,
+        client_host_name: &str,
+        client_user_name: &str,
+        host_key: &PublicKey,
+        address: IpAddr,
+    ) -> Result<(), HostbasedError> {
+        let host = normalize_host_name(client_host_name).to_ascii_lowercase();
+        if !host_name_matches(&host, address).await {
+            return Err(HostbasedError::AddressMismatch { host, address });
+        }
+        if !self.shosts_equiv.allows(&host, client_user_name, user_name) {
+            return Err(HostbasedError::NotEquivalent {
+                host,
+                client_user: client_user_name.to_string(),
+                user: user_name.to_string(),
+            });
+        }
+        KnownHostsVerifier::new(self.known_hosts.clone(), HostKeyPolicy::Strict)
+            .with_signature_policy(self.signature_policy)
+            .verify(&host, 22, host_key)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use crate::api::WriteSSH;
+use crate::kex::SessionId;
+use crate::keys::{HostKeySet, KeyError, PublicKey, SignaturePolicy};
+use crate::msg::{read_strings_to_end, GlobalRequest, MsgGlobalRequest, MsgRequestSuccess, PublicKeyAlgorithm};
+
+// Host key rotation, OpenSSH PROTOCOL §2.5. After authentication the server announces all
+// of its host keys; the client asks it to prove possession of the ones it does not know
+// yet, and may then record them in known_hosts.
+
+/// The data signed to prove possession of a host key:
+/// string "hostkeys-prove-00@openssh.com", string session identifier, string host key.
+pub fn prove_data(session_id: &SessionId, host_key_blob: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    "hostkeys-prove-00@openssh.com".to_string().write_ssh(&mut data).expect("writing to a Vec cannot fail");
+    session_id.as_bytes().to_vec().write_ssh(&mut data).expect("writing to a Vec cannot fail");
+    host_key_blob.to_vec().write_ssh(&mut data).expect("writing to a Vec cannot fail");
+    data
+}
+
+/// The server's announcement of every plain host key in the set. Certificates are not
+/// announced, they are verified through their CA instead.
+pub fn announce_host_keys(host_keys: &HostKeySet) -> MsgGlobalRequest {
+    let blobs = host_keys.keys().iter().map(|key| key.public_key().to_blob()).collect();
+    MsgGlobalRequest { want_reply: false, request: GlobalRequest::HostKeys(blobs) }
+}
+
+/// Answers a hostkeys-prove-00@openssh.com request with one signature per requested key,
+/// in request order. An RSA key signs with the negotiated RSA algorithm if there is one.
+/// Fails if any requested key is not one of ours, in which case the server replies with
+/// SSH_MSG_REQUEST_FAILURE.
+pub fn prove_host_keys(
+    host_keys: &HostKeySet,
+    session_id: &SessionId,
+    requested: &[Vec<u8>],
+    negotiated: &PublicKeyAlgorithm,
+) -> Result<MsgRequestSuccess, KeyError> {
+    let mut data = Vec::new();
+    for blob in requested {
+        let key = host_keys
+            .keys()
+            .iter()
+            .find(|key| key.public_key().to_blob() == *blob)
+            .ok_or_else(|| KeyError::Unsupported("Proof requested for an unknown host key".to_string()))?;
+        let algorithms = key.public_key().signature_algorithms(&SignaturePolicy::default());
+        let negotiated = negotiated.signature_algorithm();
+        let algorithm = if algorithms.contains(&negotiated) { &negotiated } else { &algorithms[0] };
+        key.sign(algorithm, &prove_data(session_id, blob))?.write_ssh(&mut data)?;
+    }
+    Ok(MsgRequestSuccess { data })
+}
+
+/// The announced host keys the client does not know yet. Blobs of unsupported key types
+/// are skipped, as is any certificate.
+pub fn unknown_host_keys(announced: &[Vec<u8>], known: &[PublicKey]) -> Vec<PublicKey> {
+    announced
+        .iter()
+        .filter_map(|blob| PublicKey::from_blob(blob).ok())
+        .filter(|key| !matches!(key, PublicKey::Certificate(_)) && !known.contains(key))
+        .collect()
+}
+
+/// The client's request to prove possession of `keys`.
+pub fn prove_request(keys: &[PublicKey]) -> MsgGlobalRequest {
+    let blobs = keys.iter().map(PublicKey::to_blob).collect();
+    MsgGlobalRequest { want_reply: true, request: GlobalRequest::HostKeysProve(blobs) }
+}
+
+/// Checks the server's answer to `prove_request(keys)`: one valid signature per key,
+/// bound to this session.
+pub fn verify_host_key_proofs(
+    session_id: &SessionId,
+    keys: &[PublicKey],
+    response: &MsgRequestSuccess,
+    policy: &SignaturePolicy,
+) -> Result<(), KeyError> {
+    let signatures = read_strings_to_end(&response.data)?;
+    if signatures.len() != keys.len() {
+        return Err(KeyError::Signature(format!(
+            "Expected {} host key proofs, got {}",
+            keys.len(),
+            signatures.len()
+        )));
+    }
+    keys.iter()
+        .zip(&signatures)
+        .try_for_each(|(key, signature)| key.verify(&prove_data(session_id, &key.to_blob()), signature, policy))
+}
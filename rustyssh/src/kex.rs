@@ -0,0 +1,295 @@
+use crate::api::{to_ssh_name, MPInt, ReadSSH, WriteSSH};
+use crate::keys::{KeyError, PublicKey, SignaturePolicy};
+use crate::msg::PublicKeyAlgorithm;
+
+use p256::ecdh::EphemeralSecret;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+/// Hash function of a key exchange method. Used for the exchange hash H and, later, key derivation.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum KexHash {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl KexHash {
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            KexHash::Sha256 => Sha256::digest(data).to_vec(),
+            KexHash::Sha384 => Sha384::digest(data).to_vec(),
+            KexHash::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+}
+
+/// The transcript shared by every key exchange method (RFC 4253 §8).
+#[derive(Debug, Clone)]
+pub struct ExchangeHashInput {
+    pub client_version: Vec<u8>,  // string    V_C, client's identification string (CR and LF excluded)
+    pub server_version: Vec<u8>,  // string    V_S, server's identification string (CR and LF excluded)
+    pub client_kex_init: Vec<u8>, // string    I_C, payload of the client's SSH_MSG_KEXINIT
+    pub server_kex_init: Vec<u8>, // string    I_S, payload of the server's SSH_MSG_KEXINIT
+}
+
+impl ExchangeHashInput {
+    /// Assembles and hashes H. The ephemeral values are passed already typed, as `string`
+    /// for ECDH (RFC 5656 §4) or `mpint` for classic Diffie-Hellman (RFC 4253 §8).
+    pub fn exchange_hash(
+        &self,
+        hash: KexHash,
+        host_key: &[u8],
+        client_public: &impl WriteSSH,
+        server_public: &impl WriteSSH,
+        shared_secret: &MPInt,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut h = Vec::new();
+        self.client_version.write_ssh(&mut h)?;
+        self.server_version.write_ssh(&mut h)?;
+        self.client_kex_init.write_ssh(&mut h)?;
+        self.server_kex_init.write_ssh(&mut h)?;
+        host_key.to_vec().write_ssh(&mut h)?;
+        client_public.write_ssh(&mut h)?;
+        server_public.write_ssh(&mut h)?;
+        shared_secret.write_ssh(&mut h)?;
+        Ok(hash.digest(&h))
+    }
+}
+
+/// Result of a completed key exchange.
+#[derive(Debug, Clone)]
+pub struct KexOutput {
+    pub shared_secret: MPInt,   // K
+    pub exchange_hash: Vec<u8>, // H
+    pub host_key: Vec<u8>,      // K_S, the server's public host key blob
+}
+
+impl KexOutput {
+    /// Derives one key of `len` bytes for the given letter ('A'..'F') as in RFC 4253 §7.2:
+    /// K1 = HASH(K || H || X || session_id), Kn = HASH(K || H || K1 || ... || Kn-1).
+    pub fn derive_key(&self, hash: KexHash, session_id: &SessionId, letter: u8, len: usize) -> Vec<u8> {
+        let mut k_h = Vec::new();
+        self.shared_secret.write_ssh(&mut k_h).expect("writing to a Vec cannot fail");
+        k_h.extend_from_slice(&self.exchange_hash);
+
+        let mut first = k_h.clone();
+        first.push(letter);
+        first.extend_from_slice(session_id.as_bytes());
+        let mut key = hash.digest(&first);
+
+        while key.len() < len {
+            let mut next = k_h.clone();
+            next.extend_from_slice(&key);
+            key.extend_from_slice(&hash.digest(&next));
+        }
+        key.truncate(len);
+        key
+    }
+
+    /// Client side check of the server's signature over H, made with the host key K_S sent
+    /// in the reply (RFC 4253 §8, RFC 5656 §4). The signature must use the negotiated
+    /// host key algorithm, or its plain counterpart for a certificate algorithm.
+    /// Returns the host key, whose trust is left to the caller.
+    pub fn verify_host_signature(
+        &self,
+        algorithm: &PublicKeyAlgorithm,
+        signature: &[u8],
+        policy: &SignaturePolicy,
+    ) -> Result<PublicKey, KeyError> {
+        let signed_with = PublicKeyAlgorithm::read_ssh(signature)?;
+        if signed_with != algorithm.signature_algorithm() {
+            return Err(KeyError::Signature(format!(
+                "Host key signature uses {} but {} was negotiated",
+                to_ssh_name(&signed_with),
+                to_ssh_name(algorithm)
+            )));
+        }
+        let host_key = PublicKey::from_blob(&self.host_key)?;
+        host_key.verify(&self.exchange_hash, signature, policy)?;
+        Ok(host_key)
+    }
+
+    pub fn derive_session_keys(&self, hash: KexHash, session_id: &SessionId, lengths: &KeyLengths) -> SessionKeys {
+        SessionKeys {
+            iv_client_to_server: self.derive_key(hash, session_id, b'A', lengths.iv),
+            iv_server_to_client: self.derive_key(hash, session_id, b'B', lengths.iv),
+            enc_client_to_server: self.derive_key(hash, session_id, b'C', lengths.enc),
+            enc_server_to_client: self.derive_key(hash, session_id, b'D', lengths.enc),
+            mac_client_to_server: self.derive_key(hash, session_id, b'E', lengths.mac),
+            mac_server_to_client: self.derive_key(hash, session_id, b'F', lengths.mac),
+        }
+    }
+}
+
+/// The session identifier: the exchange hash H of the first key exchange of a connection
+/// (RFC 4253 §7.2). It does not change when keys are re-exchanged, and is what user
+/// authentication signatures and channel bindings are tied to.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SessionId(Vec<u8>);
+
+impl SessionId {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Key exchange state that outlives the individual exchanges of a connection.
+#[derive(Debug, Default)]
+pub struct KexState {
+    session_id: Option<SessionId>,
+    exchanges: u32,
+}
+
+impl KexState {
+    /// Records a completed exchange. The first one fixes the session identifier,
+    /// subsequent ones (rekeys) leave it untouched.
+    pub fn record_exchange(&mut self, output: &KexOutput) -> &SessionId {
+        self.exchanges += 1;
+        self.session_id.get_or_insert_with(|| SessionId(output.exchange_hash.clone()))
+    }
+
+    pub fn session_id(&self) -> Option<&SessionId> {
+        self.session_id.as_ref()
+    }
+
+    /// Number of completed exchanges; anything above one is a rekey.
+    pub fn exchanges(&self) -> u32 {
+        self.exchanges
+    }
+}
+
+/// Key sizes required by the negotiated cipher and MAC.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct KeyLengths {
+    pub iv: usize,
+    pub enc: usize,
+    pub mac: usize,
+}
+
+/// Keys derived from a key exchange (RFC 4253 §7.2).
+#[derive(Debug, PartialEq, Clone)]
+pub struct SessionKeys {
+    pub iv_client_to_server: Vec<u8>,
+    pub iv_server_to_client: Vec<u8>,
+    pub enc_client_to_server: Vec<u8>,
+    pub enc_server_to_client: Vec<u8>,
+    pub mac_client_to_server: Vec<u8>,
+    pub mac_server_to_client: Vec<u8>,
+}
+
+/// A key exchange method. The transport drives it as a state machine:
+/// the client calls `client_start` and sends the result, the server answers with
+/// `server_reply`, and the client completes with `client_finish`.
+///
+/// Signing and verifying H with the host key is left to the caller.
+pub trait KexAlgorithm: Send {
+    fn name(&self) -> &'static str;
+
+    fn hash(&self) -> KexHash;
+
+    /// Generates the client's ephemeral key and returns its public value (e.g. Q_C).
+    fn client_start(&mut self) -> std::io::Result<Vec<u8>>;
+
+    /// Generates a fresh server ephemeral key, agrees on K with the client's public value
+    /// and computes H. Returns the server's public value (e.g. Q_S) together with the output.
+    fn server_reply(
+        &mut self,
+        input: &ExchangeHashInput,
+        host_key: &[u8],
+        client_public: &[u8],
+    ) -> std::io::Result<(Vec<u8>, KexOutput)>;
+
+    /// Agrees on K with the server's public value and computes H.
+    fn client_finish(
+        &mut self,
+        input: &ExchangeHashInput,
+        host_key: &[u8],
+        server_public: &[u8],
+    ) -> std::io::Result<KexOutput>;
+}
+
+/// Key exchange methods implemented by this crate, in order of preference.
+pub const SUPPORTED_KEX_ALGORITHMS: &[&str] = &["ecdh-sha2-nistp256"];
+
+/// Instantiates the key exchange method with the given negotiated name.
+pub fn kex_algorithm_by_name(name: &str) -> Option<Box<dyn KexAlgorithm>> {
+    match name {
+        "ecdh-sha2-nistp256" => Some(Box::new(EcdhNistp256::default())),
+        _ => None,
+    }
+}
+
+/// Picks the first algorithm on the client's list that the server also supports (RFC 4253 §7.1).
+pub fn negotiate_algorithm(client_list: &[String], server_list: &[String]) -> Option<String> {
+    client_list.iter().find(|algo| server_list.contains(algo)).cloned()
+}
+
+fn invalid_public_key(_: impl std::fmt::Debug) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid ephemeral public key")
+}
+
+/// ecdh-sha2-nistp256 (RFC 5656 §4).
+#[derive(Default)]
+pub struct EcdhNistp256 {
+    client_secret: Option<(EphemeralSecret, Vec<u8>)>,
+}
+
+impl KexAlgorithm for EcdhNistp256 {
+    fn name(&self) -> &'static str {
+        "ecdh-sha2-nistp256"
+    }
+
+    fn hash(&self) -> KexHash {
+        KexHash::Sha256
+    }
+
+    fn client_start(&mut self) -> std::io::Result<Vec<u8>> {
+        let secret = EphemeralSecret::random(&mut OsRng);
+        let q_c = secret.public_key().to_sec1_bytes().to_vec();
+        self.client_secret = Some((secret, q_c.clone()));
+        Ok(q_c)
+    }
+
+    fn server_reply(
+        &mut self,
+        input: &ExchangeHashInput,
+        host_key: &[u8],
+        client_public: &[u8],
+    ) -> std::io::Result<(Vec<u8>, KexOutput)> {
+        let client_pk = p256::PublicKey::from_sec1_bytes(client_public).map_err(invalid_public_key)?;
+        let secret = EphemeralSecret::random(&mut OsRng);
+        let q_s = secret.public_key().to_sec1_bytes().to_vec();
+        let shared = secret.diffie_hellman(&client_pk);
+        let k = MPInt::from_unsigned_bytes(shared.raw_secret_bytes());
+
+        let h = input.exchange_hash(self.hash(), host_key, &client_public.to_vec(), &q_s, &k)?;
+        let output = KexOutput {
+            shared_secret: k,
+            exchange_hash: h,
+            host_key: host_key.to_vec(),
+        };
+        Ok((q_s, output))
+    }
+
+    fn client_finish(
+        &mut self,
+        input: &ExchangeHashInput,
+        host_key: &[u8],
+        server_public: &[u8],
+    ) -> std::io::Result<KexOutput> {
+        let (secret, q_c) = self.client_secret.take().ok_or_else(|| {
+            std::io::Error::other("client_finish called before client_start")
+        })?;
+        let server_pk = p256::PublicKey::from_sec1_bytes(server_public).map_err(invalid_public_key)?;
+        let shared = secret.diffie_hellman(&server_pk);
+        let k = MPInt::from_unsigned_bytes(shared.raw_secret_bytes());
+
+        let h = input.exchange_hash(self.hash(), host_key, &q_c, &server_public.to_vec(), &k)?;
+        Ok(KexOutput {
+            shared_secret: k,
+            exchange_hash: h,
+            host_key: host_key.to_vec(),
+        })
+    }
+}
@@ -0,0 +1,311 @@
+use std::process;
+
+mod api;
+mod cert;
+mod fingerprint;
+mod keys;
+mod msg;
+
+use crate::cert::{parse_validity, CertType, Certificate, CertificateRequest, DEFAULT_USER_EXTENSIONS};
+use crate::fingerprint::{display_type, FingerprintHash};
+use crate::keys::{EcdsaCurve, KeyError, PrivateKey, PublicKey};
+
+const USAGE: &str = "usage: keygen -t ed25519|ecdsa|rsa [-b bits] [-C comment] [-N passphrase] [-m format] -f file
+       keygen -y [-P passphrase] -f file
+       keygen -p [-P old_passphrase] [-N new_passphrase] [-m format] -f file
+       keygen -l [-v] [-E sha256|md5] -f file
+       keygen -s ca_key -I key_id [-h] [-n principals] [-V validity] [-z serial] [-O option]... [-P passphrase] file.pub...
+
+formats (-m): OPENSSH (default), PKCS8, PEM (PKCS#1 for RSA, SEC1 for ECDSA)
+certificate options (-O): clear, force-command=command, source-address=address_list,
+  no-<extension>, permit-<extension>, critical:name[=value], extension:name[=value]";
+
+/// Flags followed by a value; everything else that is not a flag is a file name.
+const VALUE_FLAGS: &[&str] = &["-b", "-C", "-E", "-f", "-I", "-m", "-n", "-N", "-O", "-P", "-s", "-t", "-V", "-z"];
+
+/// The private key file formats keygen can write.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Format {
+    OpenSsh,
+    Pkcs8,
+    Pem,
+}
+
+/// Command line flags, modelled on the ssh-keygen options of the same letters.
+struct Options {
+    args: Vec<String>,
+}
+
+impl Options {
+    fn value_of(&self, flag: &str) -> Option<&str> {
+        self.args.windows(2).find(|pair| pair[0] == flag).map(|pair| pair[1].as_str())
+    }
+
+    /// Every value of a flag that may be repeated, such as -O.
+    fn values_of(&self, flag: &str) -> Vec<&str> {
+        self.args.windows(2).filter(|pair| pair[0] == flag).map(|pair| pair[1].as_str()).collect()
+    }
+
+    fn positional(&self) -> Vec<&str> {
+        let mut positional = Vec::new();
+        let mut args = self.args.iter();
+        while let Some(arg) = args.next() {
+            if VALUE_FLAGS.contains(&arg.as_str()) {
+                args.next();
+            } else if !arg.starts_with('-') {
+                positional.push(arg.as_str());
+            }
+        }
+        positional
+    }
+
+    fn has(&self, flag: &str) -> bool {
+        self.args.iter().any(|arg| arg == flag)
+    }
+
+    fn file(&self) -> Result<&str, String> {
+        self.value_of("-f").ok_or_else(|| "missing -f file".to_string())
+    }
+
+    /// An empty passphrase means no passphrase, as with ssh-keygen -N "".
+    fn passphrase(&self, flag: &str) -> Option<&[u8]> {
+        self.value_of(flag).map(str::as_bytes).filter(|passphrase| !passphrase.is_empty())
+    }
+
+    fn format(&self) -> Result<Format, String> {
+        match self.value_of("-m").map(str::to_ascii_uppercase).as_deref() {
+            None | Some("OPENSSH") | Some("RFC4716") => Ok(Format::OpenSsh),
+            Some("PKCS8") => Ok(Format::Pkcs8),
+            Some("PEM") => Ok(Format::Pem),
+            Some(other) => Err(format!("unsupported key format {}", other)),
+        }
+    }
+}
+
+fn encode(key: &PrivateKey, comment: &str, format: Format, passphrase: Option<&[u8]>) -> Result<String, KeyError> {
+    match format {
+        Format::OpenSsh => key.to_openssh(comment, passphrase),
+        Format::Pkcs8 => key.to_pkcs8_pem(passphrase),
+        Format::Pem if passphrase.is_none() => key.to_pem(),
+        Format::Pem => Err(KeyError::Unsupported(
+            "encrypted PEM keys, use -m PKCS8 for an encrypted PEM file".to_string(),
+        )),
+    }
+}
+
+/// Reads a private key and its comment, pointing at -P when the key is encrypted.
+fn load_key(path: &str, passphrase: Option<&[u8]>) -> Result<(PrivateKey, String), Box<dyn std::error::Error>> {
+    keys::load_private_key(path, passphrase).map_err(|e| match e {
+        KeyError::PassphraseRequired => format!("{} is encrypted, give its passphrase with -P", path).into(),
+        e => e.into(),
+    })
+}
+
+/// Writes a private key file readable by its owner only, as ssh refuses others.
+fn write_private_key(path: &str, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+fn generate(options: &Options, key_type: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let path = options.file()?;
+    if std::path::Path::new(path).exists() {
+        return Err(format!("{} already exists", path).into());
+    }
+    let bits: Option<usize> = options.value_of("-b").map(str::parse).transpose()?;
+    let key = match key_type {
+        "ed25519" => PrivateKey::generate_ed25519(),
+        "ecdsa" => {
+            let curve = match bits.unwrap_or(256) {
+                256 => EcdsaCurve::Nistp256,
+                384 => EcdsaCurve::Nistp384,
+                521 => EcdsaCurve::Nistp521,
+                other => return Err(format!("invalid ECDSA key size {}, expected 256, 384 or 521", other).into()),
+            };
+            PrivateKey::generate_ecdsa(curve)
+        }
+        "rsa" => PrivateKey::generate_rsa(bits.unwrap_or(3072))?,
+        other => return Err(format!("unknown key type {}", other).into()),
+    };
+    println!("Generating public/private {} key pair.", key_type);
+
+    let comment = options.value_of("-C").map_or_else(default_comment, str::to_string);
+    let public_key = key.public_key();
+    write_private_key(path, &encode(&key, &comment, options.format()?, options.passphrase("-N"))?)?;
+    std::fs::write(format!("{}.pub", path), format!("{}\n", public_key.to_openssh(&comment)))?;
+
+    println!("Your identification has been saved in {}", path);
+    println!("Your public key has been saved in {}.pub", path);
+    println!("The key fingerprint is:");
+    println!("{} {}", public_key.fingerprint(), comment);
+    println!("The key's randomart image is:");
+    println!("{}", public_key.randomart(FingerprintHash::Sha256));
+    Ok(())
+}
+
+/// user@host, the comment ssh-keygen gives new keys.
+fn default_comment() -> String {
+    let user = std::env::var("USER").unwrap_or_else(|_| "user".to_string());
+    let host = std::fs::read_to_string("/etc/hostname").unwrap_or_else(|_| "localhost".to_string());
+    format!("{}@{}", user, host.trim())
+}
+
+fn print_public_key(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let (key, comment) = load_key(options.file()?, options.passphrase("-P"))?;
+    println!("{}", key.public_key().to_openssh(&comment));
+    Ok(())
+}
+
+/// Rewrites a private key with a new passphrase and/or in another format.
+fn change_passphrase(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let path = options.file()?;
+    let (key, comment) = load_key(path, options.passphrase("-P"))?;
+    let comment = options.value_of("-C").map_or(comment, str::to_string);
+    write_private_key(path, &encode(&key, &comment, options.format()?, options.passphrase("-N"))?)?;
+    println!("Your identification has been saved with the new passphrase.");
+    Ok(())
+}
+
+/// Shows the fingerprint of every public key in a .pub, authorized_keys or known_hosts
+/// style file, or of the key in a private key file.
+fn show_fingerprints(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let hash = match options.value_of("-E").map(str::to_ascii_lowercase).as_deref() {
+        None | Some("sha256") => FingerprintHash::Sha256,
+        Some("md5") => FingerprintHash::Md5,
+        Some(other) => return Err(format!("unsupported hash {}", other).into()),
+    };
+    let path = options.file()?;
+    let text = std::fs::read_to_string(path)?;
+
+    let keys: Vec<(PublicKey, String)> = if text.contains("PRIVATE KEY-----") {
+        let (key, comment) = load_key(path, options.passphrase("-P"))?;
+        vec![(key.public_key(), comment)]
+    } else {
+        text.lines().filter_map(|line| public_key_in_line(line.trim())).collect()
+    };
+    if keys.is_empty() {
+        // Report why the first line is not a public key rather than that nothing was found.
+        let first = text.lines().map(str::trim).find(|line| !line.is_empty() && !line.starts_with('#'));
+        return Err(match first.map(PublicKey::from_openssh) {
+            Some(Err(e)) => format!("{}: {}", path, e).into(),
+            _ => format!("{}: no public key found", path).into(),
+        });
+    }
+    for (key, comment) in keys {
+        let comment = if comment.is_empty() { "no comment" } else { comment.as_str() };
+        println!("{} {} {} ({})", key.bits(), key.fingerprint_with(hash), comment, display_type(&key));
+        if options.has("-v") {
+            println!("{}", key.randomart(hash));
+        }
+    }
+    Ok(())
+}
+
+/// Signs each public key file with the CA key and writes the certificate next to it, as
+/// `id_ed25519.pub` -> `id_ed25519-cert.pub`.
+fn sign_keys(options: &Options, ca_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (ca, _) = load_key(ca_path, options.passphrase("-P"))?;
+    let key_id = options.value_of("-I").ok_or("missing -I key_id")?;
+    let mut request = if options.has("-h") { CertificateRequest::host(key_id) } else { CertificateRequest::user(key_id) };
+    if let Some(principals) = options.value_of("-n") {
+        request.valid_principals = principals.split(',').map(str::to_string).collect();
+    }
+    if let Some(validity) = options.value_of("-V") {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+        (request.valid_after, request.valid_before) = parse_validity(validity, now)?;
+    }
+    if let Some(serial) = options.value_of("-z") {
+        request.serial = serial.parse().map_err(|_| format!("invalid serial number {}", serial))?;
+    }
+    for option in options.values_of("-O") {
+        apply_certificate_option(&mut request, option)?;
+    }
+
+    let files = options.positional();
+    if files.is_empty() {
+        return Err("no public keys to sign".into());
+    }
+    for path in files {
+        let text = std::fs::read_to_string(path)?;
+        let (key, comment) = PublicKey::from_openssh(text.trim())?;
+        let cert = Certificate::issue(&key, &request, &ca)?;
+        let cert_path = format!("{}-cert.pub", path.strip_suffix(".pub").unwrap_or(path));
+        std::fs::write(&cert_path, format!("{}\n", PublicKey::Certificate(Box::new(cert)).to_openssh(&comment)))?;
+
+        let kind = if request.cert_type == CertType::Host { "host" } else { "user" };
+        let principals = if request.valid_principals.is_empty() { "any principal".to_string() } else { request.valid_principals.join(",") };
+        println!("Signed {} key {}: id \"{}\" serial {} for {}", kind, cert_path, key_id, request.serial, principals);
+    }
+    Ok(())
+}
+
+/// Applies one -O option, with the same names as ssh-keygen.
+fn apply_certificate_option(request: &mut CertificateRequest, option: &str) -> Result<(), String> {
+    let (name, value) = option.split_once('=').unwrap_or((option, ""));
+    let set = |options: &mut Vec<(String, String)>, name: &str, value: &str| {
+        options.retain(|(existing, _)| existing != name);
+        options.push((name.to_string(), value.to_string()));
+    };
+    let default_extension = |name: &str| DEFAULT_USER_EXTENSIONS.iter().find(|extension| extension.eq_ignore_ascii_case(name));
+
+    if name.eq_ignore_ascii_case("clear") {
+        request.extensions.clear();
+    } else if name == "force-command" || name == "source-address" {
+        if value.is_empty() {
+            return Err(format!("{} requires a value", name));
+        }
+        set(&mut request.critical_options, name, value);
+    } else if let Some(critical) = name.strip_prefix("critical:") {
+        set(&mut request.critical_options, critical, value);
+    } else if let Some(extension) = name.strip_prefix("extension:") {
+        set(&mut request.extensions, extension, value);
+    } else if let Some(extension) = name.strip_prefix("no-").and_then(|flag| default_extension(&format!("permit-{}", flag))) {
+        request.extensions.retain(|(existing, _)| existing != extension);
+    } else if let Some(extension) = default_extension(name) {
+        set(&mut request.extensions, extension, "");
+    } else {
+        return Err(format!("unsupported certificate option {}", option));
+    }
+    Ok(())
+}
+
+/// A public key on its own or after the options or host field of an authorized_keys or
+/// known_hosts line.
+fn public_key_in_line(line: &str) -> Option<(PublicKey, String)> {
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let mut rest = line;
+    loop {
+        if let Ok(parsed) = PublicKey::from_openssh(rest) {
+            return Some(parsed);
+        }
+        rest = rest.split_once(char::is_whitespace)?.1.trim_start();
+    }
+}
+
+fn main() {
+    let options = Options { args: std::env::args().skip(1).collect() };
+    let result = if let Some(ca_path) = options.value_of("-s") {
+        sign_keys(&options, ca_path)
+    } else if let Some(key_type) = options.value_of("-t") {
+        generate(&options, &key_type.to_ascii_lowercase())
+    } else if options.has("-y") {
+        print_public_key(&options)
+    } else if options.has("-p") {
+        change_passphrase(&options)
+    } else if options.has("-l") {
+        show_fingerprints(&options)
+    } else {
+        eprintln!("{}", USAGE);
+        process::exit(1);
+    };
+    if let Err(e) = result {
+        eprintln!("keygen: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,417 @@
+use crate::keys::{KeyError, PublicKey, SignaturePolicy};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+// known_hosts files as read by OpenSSH (sshd(8), SSH_KNOWN_HOSTS FILE FORMAT): an optional
+// marker, a comma-separated list of host patterns or a single hashed host, and a public key.
+
+/// Why a host key was not accepted.
+#[derive(Debug, thiserror::Error)]
+pub enum HostKeyError {
+    #[error("Host key for {host} has changed: presented {presented}, known {known:?}")]
+    Changed {
+        host: String,
+        presented: String,  // fingerprint of the key the server presented
+        known: Vec<String>, // fingerprints of the recorded keys of the same type
+    },
+    #[error("Host key for {host} is revoked: {fingerprint}")]
+    Revoked { host: String, fingerprint: String },
+    #[error("No known host key for {host}: {fingerprint}")]
+    Unknown { host: String, fingerprint: String },
+    #[error("Host certificate for {host} rejected: {source}")]
+    Certificate { host: String, source: KeyError },
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Decides whether the host key presented during key exchange belongs to the server.
+pub trait HostKeyVerifier {
+    fn verify(&mut self, host: &str, port: u16, key: &PublicKey) -> Result<(), HostKeyError>;
+}
+
+/// How a host without a recorded key is treated.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HostKeyPolicy {
+    /// Only recorded keys (or certificates from a recorded authority) are accepted.
+    Strict,
+    /// Trust on first use: the key of a host without any recorded key of that type is
+    /// accepted and recorded. A changed key is still rejected.
+    AcceptNew,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Marker {
+    CertAuthority, // @cert-authority
+    Revoked,       // @revoked
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum HostPatterns {
+    /// Comma-separated patterns with `*` and `?` wildcards, negated by a leading `!`.
+    List(Vec<String>),
+    /// `|1|base64(salt)|base64(HMAC-SHA1(salt, host))`
+    Hashed { salt: Vec<u8>, hash: Vec<u8> },
+}
+
+impl HostPatterns {
+    /// Whether the patterns match `name`, which is `host` or `[host]:port` for
+    /// non-default ports.
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        match self {
+            HostPatterns::Hashed { salt, hash } => hash_host(salt, &name) == *hash,
+            HostPatterns::List(patterns) => {
+                let mut matched = false;
+                for pattern in patterns {
+                    let pattern = pattern.to_ascii_lowercase();
+                    match pattern.strip_prefix('!') {
+                        Some(negated) if wildcard_match(negated, &name) => return false,
+                        Some(_) => {}
+                        None => matched |= wildcard_match(&pattern, &name),
+                    }
+                }
+                matched
+            }
+        }
+    }
+
+    /// Whether this entry names exactly `name`, without wildcards or other hosts.
+    fn is_only(&self, name: &str) -> bool {
+        match self {
+            HostPatterns::List(patterns) => patterns.len() == 1 && patterns[0].eq_ignore_ascii_case(name),
+            HostPatterns::Hashed { .. } => self.matches(name),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct KnownHostEntry {
+    pub marker: Option<Marker>,
+    pub hosts: HostPatterns,
+    pub key: PublicKey,
+    pub comment: String,
+    pub line: usize, // 1-based line number in the file
+}
+
+/// The name known_hosts entries are matched against.
+pub fn host_lookup_name(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+/// Hashes a host name for a `|1|` entry, as OpenSSH's HashKnownHosts does.
+pub fn hash_host(salt: &[u8], name: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(salt).expect("HMAC accepts any key length");
+    mac.update(name.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Matches `name` against a pattern where `*` matches any run of characters and `?`
+/// matches exactly one.
+pub(crate) fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (pattern.as_bytes(), name.as_bytes());
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            n = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Parses one line; comments, blank lines and entries with unsupported keys give `None`.
+fn parse_entry(line: &str, number: usize) -> Option<KnownHostEntry> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (marker, rest) = match line.split_once(char::is_whitespace)? {
+        ("@cert-authority", rest) => (Some(Marker::CertAuthority), rest.trim_start()),
+        ("@revoked", rest) => (Some(Marker::Revoked), rest.trim_start()),
+        (marker, _) if marker.starts_with('@') => return None,
+        _ => (None, line),
+    };
+    let (hosts, key) = rest.split_once(char::is_whitespace)?;
+    let hosts = match hosts.strip_prefix("|1|") {
+        Some(hashed) => {
+            let (salt, hash) = hashed.split_once('|')?;
+            HostPatterns::Hashed { salt: BASE64.decode(salt).ok()?, hash: BASE64.decode(hash).ok()? }
+        }
+        None => HostPatterns::List(hosts.split(',').map(str::to_string).collect()),
+    };
+    let (key, comment) = PublicKey::from_openssh(key).ok()?;
+    Some(KnownHostEntry { marker, hosts, key, comment, line: number })
+}
+
+/// The outcome of looking up a host key.
+#[derive(Debug, PartialEq)]
+pub enum HostKeyStatus {
+    Trusted,
+    Unknown,
+    Changed(Vec<PublicKey>), // the recorded keys of the presented key's type
+    Revoked,
+}
+
+/// The entries of a known_hosts file.
+#[derive(Debug, Default, Clone)]
+pub struct KnownHosts {
+    pub entries: Vec<KnownHostEntry>,
+}
+
+impl KnownHosts {
+    pub fn parse(text: &str) -> Self {
+        let entries = text
+            .lines()
+            .enumerate()
+            .filter_map(|(index, line)| parse_entry(line, index + 1))
+            .collect();
+        KnownHosts { entries }
+    }
+
+    /// Reads a known_hosts file. A missing file has no entries.
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn matching(&self, name: &str, marker: Option<Marker>) -> impl Iterator<Item = &KnownHostEntry> {
+        let name = name.to_string();
+        self.entries
+            .iter()
+            .filter(move |entry| entry.marker == marker && entry.hosts.matches(&name))
+    }
+
+    /// Certificate authorities trusted for `host` on `port`.
+    pub fn authorities(&self, host: &str, port: u16) -> Vec<PublicKey> {
+        let name = host_lookup_name(host, port);
+        self.matching(&name, Some(Marker::CertAuthority)).map(|entry| entry.key.clone()).collect()
+    }
+
+    /// The plain keys recorded for `host` on `port`.
+    pub fn host_keys(&self, host: &str, port: u16) -> Vec<PublicKey> {
+        let name = host_lookup_name(host, port);
+        self.matching(&name, None).map(|entry| entry.key.clone()).collect()
+    }
+
+    fn is_revoked(&self, name: &str, key: &PublicKey) -> bool {
+        self.matching(name, Some(Marker::Revoked)).any(|entry| entry.key == *key)
+    }
+
+    /// Looks up a plain (non-certificate) host key.
+    pub fn check(&self, host: &str, port: u16, key: &PublicKey) -> HostKeyStatus {
+        let name = host_lookup_name(host, port);
+        if self.is_revoked(&name, key) {
+            return HostKeyStatus::Revoked;
+        }
+        let same_type: Vec<PublicKey> = self
+            .matching(&name, None)
+            .filter(|entry| entry.key.key_type() == key.key_type())
+            .map(|entry| entry.key.clone())
+            .collect();
+        if same_type.contains(key) {
+            HostKeyStatus::Trusted
+        } else if same_type.is_empty() {
+            HostKeyStatus::Unknown
+        } else {
+            HostKeyStatus::Changed(same_type)
+        }
+    }
+}
+
+/// A `HostKeyVerifier` backed by a known_hosts file.
+///
+/// Host certificates are accepted when signed by an `@cert-authority` for the host and
+/// valid for its name; a certificate from an unknown authority is checked as its plain
+/// key, like OpenSSH does. Revoked keys and authorities are always rejected.
+pub struct KnownHostsVerifier {
+    known_hosts: KnownHosts,
+    path: Option<std::path::PathBuf>,
+    policy: HostKeyPolicy,
+    signature_policy: SignaturePolicy,
+}
+
+impl KnownHostsVerifier {
+    /// Loads the file at `path`. Keys accepted under `HostKeyPolicy::AcceptNew` are
+    /// appended to it.
+    pub fn load(path: impl Into<std::path::PathBuf>, policy: HostKeyPolicy) -> std::io::Result<Self> {
+        let path = path.into();
+        Ok(KnownHostsVerifier {
+            known_hosts: KnownHosts::load(&path)?,
+            path: Some(path),
+            policy,
+            signature_policy: SignaturePolicy::default(),
+        })
+    }
+
+    /// A verifier over in-memory entries; newly accepted keys are only remembered.
+    pub fn new(known_hosts: KnownHosts, policy: HostKeyPolicy) -> Self {
+        KnownHostsVerifier { known_hosts, path: None, policy, signature_policy: SignaturePolicy::default() }
+    }
+
+    pub fn with_signature_policy(mut self, signature_policy: SignaturePolicy) -> Self {
+        self.signature_policy = signature_policy;
+        self
+    }
+
+    pub fn known_hosts(&self) -> &KnownHosts {
+        &self.known_hosts
+    }
+
+    fn check_certificate(&self, host: &str, port: u16, cert: &crate::cert::Certificate) -> Option<Result<(), HostKeyError>> {
+        let authorities = self.known_hosts.authorities(host, port);
+        if !authorities.contains(&cert.signature_key) {
+            return None;
+        }
+        let name = host_lookup_name(host, port);
+        if self.known_hosts.is_revoked(&name, &cert.signature_key) || self.known_hosts.is_revoked(&name, &cert.key) {
+            let fingerprint = cert.key.fingerprint();
+            return Some(Err(HostKeyError::Revoked { host: name, fingerprint }));
+        }
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        Some(
+            cert.check_host(host, &authorities, now, &self.signature_policy)
+                .map_err(|source| HostKeyError::Certificate { host: name, source }),
+        )
+    }
+
+    fn record(&mut self, host: &str, port: u16, key: &PublicKey) -> Result<(), HostKeyError> {
+        let name = host_lookup_name(host, port);
+        let line = format!("{} {}", name, key.to_openssh(""));
+        if let Some(path) = &self.path {
+            use std::io::Write;
+            let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", line)?;
+        }
+        let number = self.known_hosts.entries.iter().map(|entry| entry.line).max().unwrap_or(0) + 1;
+        self.known_hosts.entries.extend(parse_entry(&line, number));
+        Ok(())
+    }
+}
+
+impl HostKeyVerifier for KnownHostsVerifier {
+    fn verify(&mut self, host: &str, port: u16, key: &PublicKey) -> Result<(), HostKeyError> {
+        if let PublicKey::Certificate(cert) = key {
+            if let Some(result) = self.check_certificate(host, port, cert) {
+                return result;
+            }
+            return self.verify(host, port, &cert.key);
+        }
+
+        let name = host_lookup_name(host, port);
+        let fingerprint = key.fingerprint();
+        match self.known_hosts.check(host, port, key) {
+            HostKeyStatus::Trusted => Ok(()),
+            HostKeyStatus::Revoked => Err(HostKeyError::Revoked { host: name, fingerprint }),
+            HostKeyStatus::Changed(known) => Err(HostKeyError::Changed {
+                host: name,
+                presented: fingerprint,
+                known: known.iter().map(PublicKey::fingerprint).collect(),
+            }),
+            HostKeyStatus::Unknown => match self.policy {
+                HostKeyPolicy::Strict => Err(HostKeyError::Unknown { host: name, fingerprint }),
+                HostKeyPolicy::AcceptNew => self.record(host, port, key),
+            },
+        }
+    }
+}
+
+/// Replaces the keys recorded for `hostname` with `keys`, as after a proven host key
+/// rotation. Only unmarked entries naming exactly this host (plain or hashed) are touched:
+/// entries for other hosts, marked entries and comments are kept as they are. New keys are
+/// added hashed if the host was recorded hashed.
+///
+/// Like OpenSSH, returns `false` and leaves the file alone if an unmarked entry matches the
+/// host among other names or through a wildcard, since replacing it would change what is
+/// trusted for those names too. The file is replaced by renaming a complete copy over it,
+/// so an interrupted update never leaves it truncated.
+pub fn update_host_keys(
+    path: impl AsRef<std::path::Path>,
+    hostname: &str,
+    keys: &[PublicKey],
+) -> std::io::Result<bool> {
+    let path = path.as_ref();
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut recorded = Vec::new();
+    let mut hashed = false;
+    let mut lines = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        match parse_entry(line, index + 1) {
+            Some(entry) if entry.marker.is_none() && entry.hosts.is_only(hostname) => {
+                hashed |= matches!(entry.hosts, HostPatterns::Hashed { .. });
+                if keys.contains(&entry.key) && !recorded.contains(&entry.key) {
+                    recorded.push(entry.key);
+                    lines.push(line.to_string());
+                }
+            }
+            Some(entry) if entry.marker.is_none() && entry.hosts.matches(hostname) => return Ok(false),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    for key in keys.iter().filter(|key| !recorded.contains(key)) {
+        let name = if hashed { hashed_host_name(hostname) } else { hostname.to_string() };
+        lines.push(format!("{} {}", name, key.to_openssh("")));
+    }
+
+    let mut updated = lines.join("\n");
+    updated.push('\n');
+    replace_file(path, updated.as_bytes())?;
+    Ok(true)
+}
+
+/// A `|1|` host field for `name` with a fresh salt.
+fn hashed_host_name(name: &str) -> String {
+    let mut salt = [0u8; 20];
+    rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut salt);
+    format!("|1|{}|{}", BASE64.encode(salt), BASE64.encode(hash_host(&salt, &name.to_ascii_lowercase())))
+}
+
+/// Writes `contents` to a temporary file next to `path`, with the permissions of the
+/// existing file, and renames it over `path`.
+fn replace_file(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    let file_name = path.file_name().ok_or_else(|| std::io::Error::other("Not a file path"))?;
+    let mut temporary_name = std::ffi::OsString::from(".");
+    temporary_name.push(file_name);
+    temporary_name.push(format!(".{}.tmp", std::process::id()));
+    let temporary = path.with_file_name(temporary_name);
+
+    let written = std::fs::write(&temporary, contents).and_then(|()| {
+        match std::fs::metadata(path) {
+            Ok(metadata) => std::fs::set_permissions(&temporary, metadata.permissions())?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        std::fs::File::open(&temporary)?.sync_all()?;
+        std::fs::rename(&temporary, path)
+    });
+    if written.is_err() {
+        std::fs::remove_file(&temporary).ok();
+    }
+    written
+}
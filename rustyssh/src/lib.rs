@@ -1,5 +1,18 @@
-mod api;
-mod msg;
+pub mod api;
+pub mod auth_limits;
+pub mod auth_methods;
+pub mod authorized_keys;
+pub mod cert;
+pub mod fingerprint;
+pub mod hostbased;
+pub mod hostkeys;
+pub mod kex;
+pub mod keys;
+pub mod known_hosts;
+pub mod msg;
+pub mod totp;
+pub mod transport;
+pub mod userauth;
 
 #[cfg(test)]
 mod tests;
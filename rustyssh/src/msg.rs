@@ -1,25 +1,45 @@
-use crate::api::{ReadSSH, WriteSSH};
+use crate::api::{MPInt, ReadSSH, WriteSSH};
 pub use ::rustyssh_derive::{ReadSSH, WriteSSH};
 
 pub trait SSHMagic {
     const MAGIC: u8;
 }
 
+/// The negotiated methods that give meaning to the method-specific message numbers:
+/// 30-49 belong to the key exchange method and 60-79 to the user authentication method
+/// (RFC 4250 §4.1.2). `None` means nothing has been negotiated yet.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct MessageContext {
+    pub kex_method: Option<KeyExchangeMethod>,
+    pub auth_method: Option<AuthenticationMethod>,
+}
+
+/// Reads a message outside of any negotiated method context.
+/// Method-specific message numbers are rejected, see `read_next_message_in`.
 #[allow(dead_code)]
-pub fn read_next_message<R: std::io::Read>(mut reader: R) -> Result<SSHMsg, std::io::Error> {
+pub fn read_next_message<R: std::io::Read>(reader: R) -> Result<SSHMsg, std::io::Error> {
+    read_next_message_in(reader, &MessageContext::default())
+}
+
+#[allow(dead_code)]
+pub fn read_next_message_in<R: std::io::Read>(
+    mut reader: R,
+    context: &MessageContext,
+) -> Result<SSHMsg, std::io::Error> {
     let magic: u8 = u8::read_ssh(&mut reader)?;
 
     match magic {
+        30..=49 => read_kex_method_message(magic, reader, context.kex_method.as_ref()),
+        60..=79 => read_auth_method_message(magic, reader, context.auth_method.as_ref()),
         MsgDisconnect::MAGIC => MsgDisconnect::read_ssh(reader).map(SSHMsg::Disconnect),
         MsgIgnore::MAGIC => MsgIgnore::read_ssh(reader).map(SSHMsg::Ignore),
         MsgUnimplemented::MAGIC => MsgUnimplemented::read_ssh(reader).map(SSHMsg::Unimplemented),
         MsgDebug::MAGIC => MsgDebug::read_ssh(reader).map(SSHMsg::Debug),
         MsgServiceRequest::MAGIC => MsgServiceRequest::read_ssh(reader).map(SSHMsg::ServiceRequest),
         MsgServiceAccept::MAGIC => MsgServiceAccept::read_ssh(reader).map(SSHMsg::ServiceAccept),
+        MsgExtInfo::MAGIC => MsgExtInfo::read_ssh(reader).map(SSHMsg::ExtInfo),
         MsgKexInit::MAGIC => MsgKexInit::read_ssh(reader).map(SSHMsg::KexInit),
         MsgNewKeys::MAGIC => MsgNewKeys::read_ssh(reader).map(SSHMsg::NewKeys),
-        MsgKexECDHInit::MAGIC => MsgKexECDHInit::read_ssh(reader).map(SSHMsg::KexECDHInit),
-        MsgKexECDHReply::MAGIC => MsgKexECDHReply::read_ssh(reader).map(SSHMsg::KexECDHReply),
         MsgUserauthRequest::MAGIC => MsgUserauthRequest::read_ssh(reader).map(SSHMsg::UserauthRequest),
         MsgUserauthFailure::MAGIC => MsgUserauthFailure::read_ssh(reader).map(SSHMsg::UserauthFailure),
         MsgUserauthSuccess::MAGIC => MsgUserauthSuccess::read_ssh(reader).map(SSHMsg::UserauthSuccess),
@@ -45,6 +65,71 @@ pub fn read_next_message<R: std::io::Read>(mut reader: R) -> Result<SSHMsg, std:
     }
 }
 
+fn read_kex_method_message<R: std::io::Read>(
+    magic: u8,
+    reader: R,
+    kex_method: Option<&KeyExchangeMethod>,
+) -> Result<SSHMsg, std::io::Error> {
+    use KeyExchangeMethod::*;
+
+    match kex_method {
+        Some(ecdh__sha2__nistp256 | ecdh__sha2__nistp384 | ecdh__sha2__nistp521 | curve25519__sha256) => match magic {
+            MsgKexECDHInit::MAGIC => MsgKexECDHInit::read_ssh(reader).map(SSHMsg::KexECDHInit),
+            MsgKexECDHReply::MAGIC => MsgKexECDHReply::read_ssh(reader).map(SSHMsg::KexECDHReply),
+            _ => Err(method_message_error(magic, kex_method)),
+        },
+        Some(
+            diffie__hellman__group1__sha1
+            | diffie__hellman__group14__sha1
+            | diffie__hellman__group14__sha256
+            | diffie__hellman__group16__sha512
+            | diffie__hellman__group18__sha512,
+        ) => match magic {
+            MsgKexDHInit::MAGIC => MsgKexDHInit::read_ssh(reader).map(SSHMsg::KexDHInit),
+            MsgKexDHReply::MAGIC => MsgKexDHReply::read_ssh(reader).map(SSHMsg::KexDHReply),
+            _ => Err(method_message_error(magic, kex_method)),
+        },
+        Some(diffie__hellman__group__exchange__sha1 | diffie__hellman__group__exchange__sha256) => match magic {
+            MsgKexDHGexRequestOld::MAGIC => MsgKexDHGexRequestOld::read_ssh(reader).map(SSHMsg::KexDHGexRequestOld),
+            MsgKexDHGexGroup::MAGIC => MsgKexDHGexGroup::read_ssh(reader).map(SSHMsg::KexDHGexGroup),
+            MsgKexDHGexInit::MAGIC => MsgKexDHGexInit::read_ssh(reader).map(SSHMsg::KexDHGexInit),
+            MsgKexDHGexReply::MAGIC => MsgKexDHGexReply::read_ssh(reader).map(SSHMsg::KexDHGexReply),
+            MsgKexDHGexRequest::MAGIC => MsgKexDHGexRequest::read_ssh(reader).map(SSHMsg::KexDHGexRequest),
+            _ => Err(method_message_error(magic, kex_method)),
+        },
+        _ => Err(method_message_error(magic, kex_method)),
+    }
+}
+
+fn read_auth_method_message<R: std::io::Read>(
+    magic: u8,
+    reader: R,
+    auth_method: Option<&AuthenticationMethod>,
+) -> Result<SSHMsg, std::io::Error> {
+    match (auth_method, magic) {
+        (Some(AuthenticationMethod::publickey), MsgUserauthPkOk::MAGIC) => {
+            MsgUserauthPkOk::read_ssh(reader).map(SSHMsg::UserauthPkOk)
+        }
+        (Some(AuthenticationMethod::password), MsgUserauthPasswdChangeReq::MAGIC) => {
+            MsgUserauthPasswdChangeReq::read_ssh(reader).map(SSHMsg::UserauthPasswdChangeReq)
+        }
+        (Some(AuthenticationMethod::keyboard__interactive), MsgUserauthInfoRequest::MAGIC) => {
+            MsgUserauthInfoRequest::read_ssh(reader).map(SSHMsg::UserauthInfoRequest)
+        }
+        (Some(AuthenticationMethod::keyboard__interactive), MsgUserauthInfoResponse::MAGIC) => {
+            MsgUserauthInfoResponse::read_ssh(reader).map(SSHMsg::UserauthInfoResponse)
+        }
+        _ => Err(method_message_error(magic, auth_method)),
+    }
+}
+
+fn method_message_error(magic: u8, method: Option<&impl std::fmt::Debug>) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Message number {} is not defined for negotiated method {:?}", magic, method),
+    )
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 #[allow(dead_code)]
 pub enum Magic {
@@ -54,6 +139,7 @@ pub enum Magic {
     Debug = 4,                    // byte       SSH_MSG_DEBUG
     ServiceRequest = 5,           // byte       SSH_MSG_SERVICE_REQUEST
     ServiceAccept = 6,            // byte       SSH_MSG_SERVICE_ACCEPT
+    ExtInfo = 7,                  // byte       SSH_MSG_EXT_INFO (RFC 8308)
     KexInit = 20,                 // byte       SSH_MSG_KEXINIT
     NewKeys = 21,                 // byte       SSH_MSG_NEWKEYS
     KexECDHInit = 30,             // byte       SSH_MSG_KEX_ECDH_INIT (The client sends)
@@ -181,8 +267,9 @@ pub enum Service {
 pub enum AuthenticationMethod {
     publickey,
     password,
-    hostBased,
+    hostbased,
     none,
+    keyboard__interactive,
 }
 
 #[derive(Debug, PartialEq, ReadSSH, WriteSSH, Clone)] // Added Clone
@@ -239,6 +326,16 @@ pub enum SignalName {
 #[allow(non_camel_case_types, non_snake_case)]
 pub enum KeyExchangeMethod {
     ecdh__sha2__nistp256,
+    ecdh__sha2__nistp384,
+    ecdh__sha2__nistp521,
+    curve25519__sha256,
+    diffie__hellman__group1__sha1,
+    diffie__hellman__group14__sha1,
+    diffie__hellman__group14__sha256,
+    diffie__hellman__group16__sha512,
+    diffie__hellman__group18__sha512,
+    diffie__hellman__group__exchange__sha1,
+    diffie__hellman__group__exchange__sha256,
     Unknown(String),
 }
 
@@ -263,7 +360,27 @@ pub enum MACAlgorithm {
 #[allow(non_camel_case_types, non_snake_case)]
 pub enum PublicKeyAlgorithm {
     ssh__rsa,
+    rsa__sha2__256,
+    rsa__sha2__512,
     ssh__ed25519,
+    ecdsa__sha2__nistp256,
+    ecdsa__sha2__nistp384,
+    ecdsa__sha2__nistp521,
+    // OpenSSH certificates (PROTOCOL.certkeys)
+    #[ssh(name = "ssh-rsa-cert-v01@openssh.com")]
+    ssh__rsa__cert__v01,
+    #[ssh(name = "rsa-sha2-256-cert-v01@openssh.com")]
+    rsa__sha2__256__cert__v01,
+    #[ssh(name = "rsa-sha2-512-cert-v01@openssh.com")]
+    rsa__sha2__512__cert__v01,
+    #[ssh(name = "ssh-ed25519-cert-v01@openssh.com")]
+    ssh__ed25519__cert__v01,
+    #[ssh(name = "ecdsa-sha2-nistp256-cert-v01@openssh.com")]
+    ecdsa__sha2__nistp256__cert__v01,
+    #[ssh(name = "ecdsa-sha2-nistp384-cert-v01@openssh.com")]
+    ecdsa__sha2__nistp384__cert__v01,
+    #[ssh(name = "ecdsa-sha2-nistp521-cert-v01@openssh.com")]
+    ecdsa__sha2__nistp521__cert__v01,
     Unknown(String),
 }
 
@@ -334,6 +451,49 @@ impl SSHMagic for MsgServiceAccept {
     const MAGIC: u8 = Magic::ServiceAccept as u8;
 }
 
+/// Extension negotiation (RFC 8308), sent after NEWKEYS to a peer that announced
+/// "ext-info-c" or "ext-info-s" among its key exchange algorithms.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MsgExtInfo {
+    pub extensions: Vec<(String, String)>, // uint32 nr-extensions, then string name, string value
+}
+
+impl SSHMagic for MsgExtInfo {
+    const MAGIC: u8 = Magic::ExtInfo as u8;
+}
+
+impl MsgExtInfo {
+    /// Announces the signature algorithms accepted in publickey authentication, without
+    /// which clients fall back to SHA-1 "ssh-rsa" signatures for RSA keys.
+    pub fn server_sig_algs(algorithms: &[PublicKeyAlgorithm]) -> Self {
+        let names: Vec<String> = algorithms.iter().map(crate::api::to_ssh_name).collect();
+        MsgExtInfo { extensions: vec![("server-sig-algs".to_string(), names.join(","))] }
+    }
+}
+
+impl ReadSSH for MsgExtInfo {
+    fn read_ssh<R: std::io::Read>(mut reader: R) -> Result<Self, std::io::Error> {
+        let count = u32::read_ssh(&mut reader)?;
+        let mut extensions = Vec::new();
+        for _ in 0..count {
+            extensions.push((String::read_ssh(&mut reader)?, String::read_ssh(&mut reader)?));
+        }
+        Ok(MsgExtInfo { extensions })
+    }
+}
+
+impl WriteSSH for MsgExtInfo {
+    fn write_ssh<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        MsgExtInfo::MAGIC.write_ssh(writer)?;
+        (self.extensions.len() as u32).write_ssh(writer)?;
+        for (name, value) in &self.extensions {
+            name.write_ssh(writer)?;
+            value.write_ssh(writer)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, PartialEq, ReadSSH, WriteSSH, Clone)] // Added Clone
 pub struct MsgKexInit {
     pub cookie: [u8; 16],            // byte[16]     cookie (random bytes)
@@ -382,6 +542,82 @@ impl SSHMagic for MsgKexECDHReply {
     const MAGIC: u8 = Magic::KexECDHReply as u8;
 }
 
+// Method-specific messages share numbers with other methods, so their magic is not part of `Magic`.
+
+// diffie-hellman-group* (RFC 4253 §8)
+
+#[derive(Debug, PartialEq, ReadSSH, WriteSSH, Clone)]
+pub struct MsgKexDHInit {
+    pub e: MPInt, // mpint    e
+}
+
+impl SSHMagic for MsgKexDHInit {
+    const MAGIC: u8 = 30; // SSH_MSG_KEXDH_INIT
+}
+
+#[derive(Debug, PartialEq, ReadSSH, WriteSSH, Clone)]
+pub struct MsgKexDHReply {
+    pub k_s: Vec<u8>,       // string    server public host key and certificates (K_S)
+    pub f: MPInt,           // mpint     f
+    pub signature: Vec<u8>, // string    signature of H
+}
+
+impl SSHMagic for MsgKexDHReply {
+    const MAGIC: u8 = 31; // SSH_MSG_KEXDH_REPLY
+}
+
+// diffie-hellman-group-exchange-* (RFC 4419)
+
+#[derive(Debug, PartialEq, ReadSSH, WriteSSH, Clone)]
+pub struct MsgKexDHGexRequestOld {
+    pub n: u32, // uint32    n, preferred size in bits of the group the server will send
+}
+
+impl SSHMagic for MsgKexDHGexRequestOld {
+    const MAGIC: u8 = 30; // SSH_MSG_KEX_DH_GEX_REQUEST_OLD
+}
+
+#[derive(Debug, PartialEq, ReadSSH, WriteSSH, Clone)]
+pub struct MsgKexDHGexGroup {
+    pub p: MPInt, // mpint     p, safe prime
+    pub g: MPInt, // mpint     g, generator for subgroup in GF(p)
+}
+
+impl SSHMagic for MsgKexDHGexGroup {
+    const MAGIC: u8 = 31; // SSH_MSG_KEX_DH_GEX_GROUP
+}
+
+#[derive(Debug, PartialEq, ReadSSH, WriteSSH, Clone)]
+pub struct MsgKexDHGexInit {
+    pub e: MPInt, // mpint     e
+}
+
+impl SSHMagic for MsgKexDHGexInit {
+    const MAGIC: u8 = 32; // SSH_MSG_KEX_DH_GEX_INIT
+}
+
+#[derive(Debug, PartialEq, ReadSSH, WriteSSH, Clone)]
+pub struct MsgKexDHGexReply {
+    pub k_s: Vec<u8>,       // string    server public host key and certificates (K_S)
+    pub f: MPInt,           // mpint     f
+    pub signature: Vec<u8>, // string    signature of H
+}
+
+impl SSHMagic for MsgKexDHGexReply {
+    const MAGIC: u8 = 33; // SSH_MSG_KEX_DH_GEX_REPLY
+}
+
+#[derive(Debug, PartialEq, ReadSSH, WriteSSH, Clone)]
+pub struct MsgKexDHGexRequest {
+    pub min: u32, // uint32    min, minimal size in bits of an acceptable group
+    pub n: u32,   // uint32    n, preferred size in bits of the group the server will send
+    pub max: u32, // uint32    max, maximal size in bits of an acceptable group
+}
+
+impl SSHMagic for MsgKexDHGexRequest {
+    const MAGIC: u8 = 34; // SSH_MSG_KEX_DH_GEX_REQUEST
+}
+
 // User Authentication Protocol Messages (RFC 4252)
 
 #[derive(Debug, PartialEq, ReadSSH, WriteSSH)]
@@ -419,27 +655,206 @@ impl SSHMagic for MsgUserauthBanner {
     const MAGIC: u8 = Magic::UserauthBanner as u8;
 }
 
+#[derive(Debug, PartialEq, ReadSSH, WriteSSH, Clone)]
+pub struct MsgUserauthPkOk {
+    pub algorithm_name: String,   // string    public key algorithm name from the request
+    pub public_key_blob: Vec<u8>, // string    public key blob from the request
+}
+impl SSHMagic for MsgUserauthPkOk {
+    const MAGIC: u8 = 60; // SSH_MSG_USERAUTH_PK_OK (publickey)
+}
+
+#[derive(Debug, PartialEq, ReadSSH, WriteSSH, Clone)]
+pub struct MsgUserauthPasswdChangeReq {
+    pub prompt: String,       // string    prompt in ISO-10646 UTF-8 encoding [RFC3629]
+    pub language_tag: String, // string    language tag [RFC3066]
+}
+impl SSHMagic for MsgUserauthPasswdChangeReq {
+    const MAGIC: u8 = 60; // SSH_MSG_USERAUTH_PASSWD_CHANGEREQ (password)
+}
+
+/// One prompt of a keyboard-interactive info request.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct InfoPrompt {
+    pub prompt: String, // string    prompt[n] in ISO-10646 UTF-8 encoding [RFC3629]
+    pub echo: bool,     // boolean   echo[n], whether the answer may be shown while typed
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MsgUserauthInfoRequest {
+    pub name: String,             // string    name (ISO-10646 UTF-8)
+    pub instruction: String,      // string    instruction (ISO-10646 UTF-8)
+    pub language_tag: String,     // string    language tag (as defined in [RFC-3066])
+    pub prompts: Vec<InfoPrompt>, // int       num-prompts, then prompt[n] and echo[n]
+}
+impl SSHMagic for MsgUserauthInfoRequest {
+    const MAGIC: u8 = 60; // SSH_MSG_USERAUTH_INFO_REQUEST (keyboard-interactive, RFC 4256)
+}
+
+impl ReadSSH for MsgUserauthInfoRequest {
+    fn read_ssh<R: std::io::Read>(mut reader: R) -> Result<Self, std::io::Error> {
+        let name = String::read_ssh(&mut reader)?;
+        let instruction = String::read_ssh(&mut reader)?;
+        let language_tag = String::read_ssh(&mut reader)?;
+        let count = u32::read_ssh(&mut reader)?;
+        let mut prompts = Vec::new();
+        for _ in 0..count {
+            prompts.push(InfoPrompt { prompt: String::read_ssh(&mut reader)?, echo: bool::read_ssh(&mut reader)? });
+        }
+        Ok(MsgUserauthInfoRequest { name, instruction, language_tag, prompts })
+    }
+}
+
+impl WriteSSH for MsgUserauthInfoRequest {
+    fn write_ssh<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        MsgUserauthInfoRequest::MAGIC.write_ssh(writer)?;
+        self.name.write_ssh(writer)?;
+        self.instruction.write_ssh(writer)?;
+        self.language_tag.write_ssh(writer)?;
+        (self.prompts.len() as u32).write_ssh(writer)?;
+        for prompt in &self.prompts {
+            prompt.prompt.write_ssh(writer)?;
+            prompt.echo.write_ssh(writer)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Clone)]
+pub struct MsgUserauthInfoResponse {
+    pub responses: Vec<String>, // int       num-responses, then string response[n]
+}
+
+// The answers are passwords and one-time codes, so only their number is shown.
+impl std::fmt::Debug for MsgUserauthInfoResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MsgUserauthInfoResponse")
+            .field("responses", &format_args!("<{} redacted>", self.responses.len()))
+            .finish()
+    }
+}
+impl SSHMagic for MsgUserauthInfoResponse {
+    const MAGIC: u8 = 61; // SSH_MSG_USERAUTH_INFO_RESPONSE (keyboard-interactive, RFC 4256)
+}
+
+impl ReadSSH for MsgUserauthInfoResponse {
+    fn read_ssh<R: std::io::Read>(mut reader: R) -> Result<Self, std::io::Error> {
+        let count = u32::read_ssh(&mut reader)?;
+        let mut responses = Vec::new();
+        for _ in 0..count {
+            responses.push(String::read_ssh(&mut reader)?);
+        }
+        Ok(MsgUserauthInfoResponse { responses })
+    }
+}
+
+impl WriteSSH for MsgUserauthInfoResponse {
+    fn write_ssh<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        MsgUserauthInfoResponse::MAGIC.write_ssh(writer)?;
+        (self.responses.len() as u32).write_ssh(writer)?;
+        for response in &self.responses {
+            response.write_ssh(writer)?;
+        }
+        Ok(())
+    }
+}
+
 // Connection Protocol Messages (RFC 4254)
 
-#[derive(Debug, PartialEq, ReadSSH, WriteSSH)]
-pub struct MsgGlobalRequest { // Placeholder
-    pub request_name: String,
+#[derive(Debug, PartialEq, Clone)]
+pub struct MsgGlobalRequest {
     pub want_reply: bool,
-    // Request-specific data omitted for now
+    pub request: GlobalRequest, // string request name, followed by request-specific data
 }
 impl SSHMagic for MsgGlobalRequest {
     const MAGIC: u8 = Magic::GlobalRequest as u8;
 }
 
-#[derive(Debug, PartialEq, ReadSSH, WriteSSH)]
-pub struct MsgRequestSuccess { // Placeholder
-    // Port number for tcpip-forward, or other request-specific data
+/// The request name and request-specific data of SSH_MSG_GLOBAL_REQUEST (RFC 4254 §4).
+#[derive(Debug, PartialEq, Clone)]
+pub enum GlobalRequest {
+    /// hostkeys-00@openssh.com: every host key blob of the server (OpenSSH PROTOCOL §2.5).
+    HostKeys(Vec<Vec<u8>>),
+    /// hostkeys-prove-00@openssh.com: the host key blobs the client wants proven.
+    HostKeysProve(Vec<Vec<u8>>),
+    /// Any other request, with its data left unparsed.
+    Other { request_name: String, data: Vec<u8> },
+}
+
+impl GlobalRequest {
+    pub fn request_name(&self) -> &str {
+        match self {
+            GlobalRequest::HostKeys(_) => "hostkeys-00@openssh.com",
+            GlobalRequest::HostKeysProve(_) => "hostkeys-prove-00@openssh.com",
+            GlobalRequest::Other { request_name, .. } => request_name,
+        }
+    }
+}
+
+impl ReadSSH for MsgGlobalRequest {
+    fn read_ssh<R: std::io::Read>(mut reader: R) -> Result<Self, std::io::Error> {
+        let request_name = String::read_ssh(&mut reader)?;
+        let want_reply = bool::read_ssh(&mut reader)?;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let request = match request_name.as_str() {
+            "hostkeys-00@openssh.com" => GlobalRequest::HostKeys(read_strings_to_end(&data)?),
+            "hostkeys-prove-00@openssh.com" => GlobalRequest::HostKeysProve(read_strings_to_end(&data)?),
+            _ => GlobalRequest::Other { request_name, data },
+        };
+        Ok(MsgGlobalRequest { want_reply, request })
+    }
+}
+
+impl WriteSSH for MsgGlobalRequest {
+    fn write_ssh<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        MsgGlobalRequest::MAGIC.write_ssh(writer)?;
+        self.request.request_name().to_string().write_ssh(writer)?;
+        self.want_reply.write_ssh(writer)?;
+        match &self.request {
+            GlobalRequest::HostKeys(blobs) | GlobalRequest::HostKeysProve(blobs) => {
+                blobs.iter().try_for_each(|blob| blob.write_ssh(writer))
+            }
+            GlobalRequest::Other { data, .. } => writer.write_all(data),
+        }
+    }
+}
+
+/// Reads consecutive `string`s until the data is exhausted.
+pub fn read_strings_to_end(data: &[u8]) -> Result<Vec<Vec<u8>>, std::io::Error> {
+    let mut reader = std::io::Cursor::new(data);
+    let mut strings = Vec::new();
+    while (reader.position() as usize) < data.len() {
+        strings.push(Vec::<u8>::read_ssh(&mut reader)?);
+    }
+    Ok(strings)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MsgRequestSuccess {
+    // Response-specific data, e.g. the port number for tcpip-forward or the signatures
+    // answering hostkeys-prove-00@openssh.com. Its layout depends on the request.
     pub data: Vec<u8>,
 }
 impl SSHMagic for MsgRequestSuccess {
     const MAGIC: u8 = Magic::RequestSuccess as u8;
 }
 
+impl ReadSSH for MsgRequestSuccess {
+    fn read_ssh<R: std::io::Read>(mut reader: R) -> Result<Self, std::io::Error> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(MsgRequestSuccess { data })
+    }
+}
+
+impl WriteSSH for MsgRequestSuccess {
+    fn write_ssh<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        MsgRequestSuccess::MAGIC.write_ssh(writer)?;
+        writer.write_all(&self.data)
+    }
+}
+
 #[derive(Debug, PartialEq, ReadSSH, WriteSSH)]
 pub struct MsgRequestFailure { // Placeholder
     // No fields for this message as per RFC 4254, Section 4
@@ -564,14 +979,26 @@ pub enum SSHMsg {
     Debug(MsgDebug),
     ServiceRequest(MsgServiceRequest),
     ServiceAccept(MsgServiceAccept),
+    ExtInfo(MsgExtInfo),
     KexInit(MsgKexInit),
     NewKeys(MsgNewKeys),
     KexECDHInit(MsgKexECDHInit),
     KexECDHReply(MsgKexECDHReply),
+    KexDHInit(MsgKexDHInit),
+    KexDHReply(MsgKexDHReply),
+    KexDHGexRequestOld(MsgKexDHGexRequestOld),
+    KexDHGexGroup(MsgKexDHGexGroup),
+    KexDHGexInit(MsgKexDHGexInit),
+    KexDHGexReply(MsgKexDHGexReply),
+    KexDHGexRequest(MsgKexDHGexRequest),
     UserauthRequest(MsgUserauthRequest),
     UserauthFailure(MsgUserauthFailure),
     UserauthSuccess(MsgUserauthSuccess),
     UserauthBanner(MsgUserauthBanner),
+    UserauthPkOk(MsgUserauthPkOk),
+    UserauthPasswdChangeReq(MsgUserauthPasswdChangeReq),
+    UserauthInfoRequest(MsgUserauthInfoRequest),
+    UserauthInfoResponse(MsgUserauthInfoResponse),
     GlobalRequest(MsgGlobalRequest),
     RequestSuccess(MsgRequestSuccess),
     RequestFailure(MsgRequestFailure),
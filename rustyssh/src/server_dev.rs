@@ -1,21 +1,48 @@
-use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr};
 use std::process::Stdio;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 use tokio::process::Command;
 use tokio::time::{timeout, Duration};
 
 pub mod api;
+pub mod auth_limits;
+pub mod auth_methods;
+pub mod authorized_keys;
+pub mod cert;
+pub mod fingerprint;
+pub mod hostbased;
+pub mod hostkeys;
+pub mod kex;
+pub mod keys;
+pub mod known_hosts;
 pub mod msg;
-
-use crate::api::{ReadSSH, WriteSSH};
+pub mod totp;
+pub mod transport;
+pub mod userauth;
+
+use crate::api::{from_ssh_name, to_ssh_name, ReadSSH, WriteSSH};
+use crate::auth_limits::{valid_user_name, AttemptOutcome, AuthAttempts, AuthLimitExceeded, AuthLimits, FailureTracker};
+use crate::auth_methods::{AuthenticationMethods, Groups, MethodChainHandler};
+use crate::authorized_keys::{AuthorizedKey, AuthorizedKeys};
+use crate::fingerprint::FingerprintHash;
+use crate::hostbased::{HostbasedPolicy, ShostsEquiv};
+use crate::kex::*;
+use crate::keys::{load_private_key, HostKeySet, PrivateKey, PublicKey};
+use crate::known_hosts::KnownHosts;
 use crate::msg::*;
-
-use ed25519_dalek::{Signer, SigningKey};
-use p256::ecdh::EphemeralSecret;
+use crate::totp::{TotpConfig, TotpSecrets, TotpVerifier};
+use crate::transport::{
+    client_message_allowed, PacketCipher, PacketReader, PacketWriter, SUPPORTED_CIPHERS, SUPPORTED_MACS,
+};
+use crate::userauth::{
+    banner_for, handle_info_response, handle_request, AuthHandler, AuthReply, AuthRequest, AuthResult, InfoExchange,
+    Restrictions,
+};
+
+use ed25519_dalek::SigningKey;
 use rand::rngs::OsRng;
-use sha2::{Digest, Sha256};
 
 /// Formats a byte slice into a Python-style `repr()` string for readable logging.
 fn format_bytes_as_repr(bytes: &[u8]) -> String {
@@ -36,90 +63,10 @@ fn format_bytes_as_repr(bytes: &[u8]) -> String {
     s
 }
 
-struct CryptoState {
-    key: Vec<u8>,
-    // This simulates the state (keystream position) for a stream cipher.
-    bytes_processed: usize,
-}
-
-/// A toy stream cipher using a repeating XOR key. This simulates a real stream cipher like AES-CTR.
-fn xor_with_keystream(data: &mut [u8], key: &[u8], start_offset: usize) {
-    if key.is_empty() {
-        return;
-    }
-    for (i, byte) in data.iter_mut().enumerate() {
-        *byte ^= key[(start_offset + i) % key.len()];
-    }
-}
-
-/// Applies the toy XOR cipher and appends a dummy MAC.
-fn encrypt_and_mac(
-    crypto: &mut CryptoState,
-    _sequence_num: u32,
-    plaintext_packet: &[u8],
-) -> Result<Vec<u8>, std::io::Error> {
-    println!("[Server] !! STUB: 'Encrypting' packet with XOR cipher.");
-    const MAC_LEN: usize = 32; // for hmac-sha2-256
-    let mut encrypted_packet = plaintext_packet.to_vec();
-    xor_with_keystream(
-        &mut encrypted_packet,
-        &crypto.key,
-        crypto.bytes_processed,
-    );
-    crypto.bytes_processed += encrypted_packet.len();
-    encrypted_packet.extend_from_slice(&vec![0u8; MAC_LEN]); // Dummy MAC
-    Ok(encrypted_packet)
-}
-
-/// Reverses the toy XOR cipher and verifies the dummy MAC.
-fn decrypt_and_verify(
-    crypto: &mut CryptoState,
-    _sequence_num: u32,
-    packet_with_mac: &[u8],
-) -> Result<Vec<u8>, std::io::Error> {
-    println!("[Server] !! STUB: 'Decrypting' packet with XOR cipher.");
-    const MAC_LEN: usize = 32; // for hmac-sha2-256
-    if packet_with_mac.len() < MAC_LEN {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Packet too small for MAC",
-        ));
-    }
-    let data_len = packet_with_mac.len() - MAC_LEN;
-    let mut encrypted_data = packet_with_mac[..data_len].to_vec();
-    xor_with_keystream(&mut encrypted_data, &crypto.key, crypto.bytes_processed);
-    crypto.bytes_processed += encrypted_data.len();
-    Ok(encrypted_data)
-}
-
-fn build_packet(payload: &[u8]) -> Vec<u8> {
-    let block_size = 8;
-
-    let unpadded_len = 4 + 1 + payload.len();
-    let remainder = unpadded_len % block_size;
-    let mut padding_len = if remainder == 0 {
-        0
-    } else {
-        block_size - remainder
-    };
-    if padding_len < 4 {
-        padding_len += block_size;
-    }
-
-    let packet_length_val = 1 + payload.len() + padding_len;
-    let mut packet = Vec::with_capacity(4 + packet_length_val);
-    packet.extend_from_slice(&(packet_length_val as u32).to_be_bytes());
-    packet.push(padding_len as u8);
-    packet.extend_from_slice(payload);
-    packet.extend_from_slice(&vec![0u8; padding_len]);
-
-    packet
-}
-
 async fn send_packet<W: AsyncWriteExt + Unpin>(
     writer: &mut W,
     msg: &(impl WriteSSH + SSHMagic + std::fmt::Debug),
-    server_crypto: &mut Option<CryptoState>,
+    packets: &mut PacketWriter,
 ) -> std::io::Result<()> {
     let mut payload_buf = Vec::new();
     msg.write_ssh(&mut payload_buf)?;
@@ -131,13 +78,7 @@ async fn send_packet<W: AsyncWriteExt + Unpin>(
         format_bytes_as_repr(&payload_buf)
     );
 
-    let framed_packet = build_packet(&payload_buf);
-
-    let packet_to_send = if let Some(ref mut crypto) = server_crypto {
-        encrypt_and_mac(crypto, 0, &framed_packet)?
-    } else {
-        framed_packet
-    };
+    let packet_to_send = packets.seal(&payload_buf);
 
     println!(
         "[Server] -> Full packet to be sent ({} bytes): {}",
@@ -147,23 +88,348 @@ async fn send_packet<W: AsyncWriteExt + Unpin>(
     writer.write_all(&packet_to_send).await
 }
 
+fn disconnect_message(code: DisconnectCode, description: impl Into<String>) -> MsgDisconnect {
+    MsgDisconnect { code, description: description.into(), language: String::new() }
+}
+
+/// Host key files given as `--host-key <path>`, which may be repeated.
+fn host_key_paths() -> Vec<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.windows(2)
+        .filter(|pair| pair[0] == "--host-key")
+        .map(|pair| pair[1].clone())
+        .collect()
+}
+
+/// Host certificates given as `--host-cert <path>` (`*-cert.pub` files), which may be repeated.
+fn host_cert_paths() -> Vec<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.windows(2)
+        .filter(|pair| pair[0] == "--host-cert")
+        .map(|pair| pair[1].clone())
+        .collect()
+}
+
+fn load_host_keys() -> Result<HostKeySet, Box<dyn std::error::Error>> {
+    let paths = host_key_paths();
+    if paths.is_empty() {
+        println!("[Server] !! No --host-key configured. Using an ephemeral ed25519 host key.");
+        return Ok(HostKeySet::new(vec![PrivateKey::Ed25519(SigningKey::generate(&mut OsRng))])?);
+    }
+    let mut host_keys = HostKeySet::default();
+    for path in paths {
+        let (key, _comment) = load_private_key(&path, None).map_err(|e| format!("{}: {}", path, e))?;
+        println!("[Server] Loaded {} host key from {}", key.public_key().key_type(), path);
+        host_keys.add(key).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    for path in host_cert_paths() {
+        let (key, _comment) = PublicKey::from_openssh(&std::fs::read_to_string(&path)?)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        let PublicKey::Certificate(cert) = key else {
+            return Err(format!("{}: not a certificate", path).into());
+        };
+        println!("[Server] Loaded host certificate {} from {}", cert.key_id, path);
+        host_keys.add_certificate(*cert).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(host_keys)
+}
+
+/// Public key files given as `--authorized-key <path>`, which may be repeated.
+fn authorized_key_paths() -> Vec<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.windows(2)
+        .filter(|pair| pair[0] == "--authorized-key")
+        .map(|pair| pair[1].clone())
+        .collect()
+}
+
+/// The value of a flag given once, such as `--totp-secrets <path>`.
+fn arg_value(flag: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.windows(2).find(|pair| pair[0] == flag).map(|pair| pair[1].clone())
+}
+
+/// authorized_keys files given as `--authorized-keys <path>`, which may be repeated.
+fn authorized_keys_paths() -> Vec<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.windows(2)
+        .filter(|pair| pair[0] == "--authorized-keys")
+        .map(|pair| pair[1].clone())
+        .collect()
+}
+
+/// The development login: user "admin" with password "password", or with any of the
+/// `--authorized-key` keys or `--authorized-keys` entries.
+///
+/// With `--totp-secrets <path>`, keyboard-interactive asks for a TOTP code instead of the
+/// password, and admin must log in with a key and then a code unless `--auth-methods` has
+/// chains for admin.
+/// With `--shosts-equiv <path>` and `--hostbased-known-hosts <path>`, users the shosts.equiv
+/// file trusts as admin may also log in from hosts with keys in the known_hosts file.
+/// With `--expire-password`, the password must be changed at the first login.
+/// With `--banner <path>`, the file is shown before login, with "%u" replaced by the user name.
+struct DevAuthHandler {
+    authorized_keys: AuthorizedKeys,
+    client_address: IpAddr, // for from=, source-address and hostbased client names
+    hostbased: Option<HostbasedPolicy>,
+    totp: Option<TotpVerifier>,
+    password: Mutex<(String, bool)>, // admin's password and whether it has expired
+    banner: Option<String>,
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+impl DevAuthHandler {
+    fn load() -> Result<Self, Box<dyn std::error::Error>> {
+        let mut authorized_keys = AuthorizedKeys::default();
+        for path in authorized_key_paths() {
+            let (key, comment) = PublicKey::from_openssh(std::fs::read_to_string(&path)?.trim())
+                .map_err(|e| format!("{}: {}", path, e))?;
+            println!("[Server] Authorized {} key {} for admin", key.key_type(), key.fingerprint());
+            authorized_keys.entries.push(AuthorizedKey { options: Default::default(), key, comment, line: 1 });
+        }
+        for path in authorized_keys_paths() {
+            let file = AuthorizedKeys::load(&path).map_err(|e| format!("{}: {}", path, e))?;
+            println!("[Server] Loaded {} authorized_keys entries for admin from {}", file.entries.len(), path);
+            authorized_keys.entries.extend(file.entries);
+        }
+        let totp = match arg_value("--totp-secrets") {
+            Some(path) => {
+                let secrets = TotpSecrets::load(&path).map_err(|e| format!("{}: {}", path, e))?;
+                println!("[Server] Loaded TOTP secrets for {} users from {}", secrets.len(), path);
+                Some(TotpVerifier::new(secrets, TotpConfig::default())?)
+            }
+            None => None,
+        };
+        let hostbased = match (arg_value("--shosts-equiv"), arg_value("--hostbased-known-hosts")) {
+            (Some(shosts_path), Some(known_hosts_path)) => {
+                let shosts_equiv = ShostsEquiv::load(&shosts_path).map_err(|e| format!("{}: {}", shosts_path, e))?;
+                let known_hosts =
+                    KnownHosts::load(&known_hosts_path).map_err(|e| format!("{}: {}", known_hosts_path, e))?;
+                println!(
+                    "[Server] Hostbased: {} shosts.equiv entries from {}, {} known hosts from {}",
+                    shosts_equiv.entries.len(),
+                    shosts_path,
+                    known_hosts.entries.len(),
+                    known_hosts_path
+                );
+                Some(HostbasedPolicy { shosts_equiv, known_hosts, ..Default::default() })
+            }
+            (None, None) => None,
+            _ => return Err("--shosts-equiv and --hostbased-known-hosts go together".into()),
+        };
+        let banner = match arg_value("--banner") {
+            Some(path) => Some(std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?),
+            None => None,
+        };
+        Ok(DevAuthHandler {
+            authorized_keys,
+            client_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            hostbased,
+            totp,
+            password: Mutex::new(("password".to_string(), std::env::args().any(|arg| arg == "--expire-password"))),
+            banner,
+        })
+    }
+
+    fn authorize_key(&self, user_name: &str, key: &PublicKey) -> Option<Restrictions> {
+        if user_name != "admin" {
+            return None;
+        }
+        self.authorized_keys.authorize(user_name, key, self.client_address, now(), &self.signature_policy())
+    }
+}
+
+impl AuthHandler for DevAuthHandler {
+    fn methods(&self, _user_name: &str) -> Vec<&'static str> {
+        if self.totp.is_some() {
+            return vec!["publickey", "keyboard-interactive"];
+        }
+        let mut methods = Vec::new();
+        if self.hostbased.is_some() {
+            methods.push("hostbased");
+        }
+        if !self.authorized_keys.entries.is_empty() {
+            methods.push("publickey");
+        }
+        methods.extend(["keyboard-interactive", "password"]);
+        methods
+    }
+
+    async fn banner(&self, user_name: &str) -> Option<String> {
+        self.banner.as_ref().map(|banner| banner.replace("%u", user_name))
+    }
+
+    async fn auth_password(&self, user_name: &str, password: &str, new_password: Option<&str>) -> AuthResult {
+        let mut account = self.password.lock().unwrap();
+        let (current, expired) = &mut *account;
+        if user_name != "admin" || password != current {
+            return AuthResult::Reject;
+        }
+        match new_password {
+            Some(new_password) if new_password.is_empty() || new_password == password => AuthResult::ChangePassword {
+                prompt: "The new password must differ from the old one.\n".to_string(),
+            },
+            Some(new_password) => {
+                println!("[Server] -- Password of {} changed.", user_name);
+                *current = new_password.to_string();
+                *expired = false;
+                AuthResult::Accept
+            }
+            None if *expired => AuthResult::ChangePassword { prompt: "Your password has expired.\n".to_string() },
+            None => AuthResult::Accept,
+        }
+    }
+
+    /// Asks for the password in a single round, or for the TOTP code.
+    async fn auth_keyboard_interactive(&self, _user_name: &str, _submethods: &[String]) -> AuthResult {
+        match &self.totp {
+            Some(totp) => totp.info_request(),
+            None => AuthResult::InfoRequest {
+                name: String::new(),
+                instruction: String::new(),
+                prompts: vec![InfoPrompt { prompt: "Password: ".to_string(), echo: false }],
+            },
+        }
+    }
+
+    async fn auth_info_response(&self, user_name: &str, responses: &[Vec<String>]) -> AuthResult {
+        let Some(totp) = &self.totp else {
+            return self.auth_password(user_name, &responses[0][0], None).await;
+        };
+        if user_name == "admin" && totp.verify_responses(user_name, responses, now()) {
+            AuthResult::Accept
+        } else {
+            AuthResult::Reject
+        }
+    }
+
+    async fn publickey_acceptable(&self, user_name: &str, key: &PublicKey) -> bool {
+        self.authorize_key(user_name, key).is_some()
+    }
+
+    async fn auth_publickey(&self, user_name: &str, key: &PublicKey) -> AuthResult {
+        match self.authorize_key(user_name, key) {
+            Some(restrictions) => AuthResult::Restricted(restrictions),
+            None => AuthResult::Reject,
+        }
+    }
+
+    async fn auth_hostbased(
+        &self,
+        user_name: &str,
+        client_host_name: &str,
+        client_user_name: &str,
+        host_key: &PublicKey,
+    ) -> AuthResult {
+        let Some(policy) = &self.hostbased else {
+            return AuthResult::Reject;
+        };
+        if user_name != "admin" {
+            return AuthResult::Reject;
+        }
+        match policy.check(user_name, client_host_name, client_user_name, host_key, self.client_address).await {
+            Ok(()) => {
+                println!("[Server] Hostbased login by {}@{}", client_user_name, client_host_name);
+                AuthResult::Accept
+            }
+            Err(e) => {
+                println!("[Server] !! Hostbased login rejected: {}", e);
+                AuthResult::Reject
+            }
+        }
+    }
+}
+
+/// The development handler under the method chains of `--auth-methods <path>`, with
+/// groups from `--groups <path>` in the /etc/group format. With TOTP secrets, admin
+/// defaults to the chain "publickey,keyboard-interactive".
+fn load_auth_handler() -> Result<MethodChainHandler<DevAuthHandler>, Box<dyn std::error::Error>> {
+    let mut policy = match arg_value("--auth-methods") {
+        Some(path) => {
+            let policy = AuthenticationMethods::load(&path).map_err(|e| format!("{}: {}", path, e))?;
+            println!("[Server] Loaded authentication method chains from {}", path);
+            policy
+        }
+        None => AuthenticationMethods::default(),
+    };
+    let groups = match arg_value("--groups") {
+        Some(path) => Groups::load(&path).map_err(|e| format!("{}: {}", path, e))?,
+        None => Groups::default(),
+    };
+    let handler = DevAuthHandler::load()?;
+    if handler.totp.is_some() && policy.chains("admin", &groups.of("admin")).is_none() {
+        policy.add_user_rule("admin", vec![vec!["publickey".to_string(), "keyboard-interactive".to_string()]]);
+    }
+    Ok(MethodChainHandler::new(handler, policy, groups))
+}
+
+/// `--max-auth-tries <n>` and `--login-grace-time <seconds>`, otherwise the defaults.
+fn load_auth_limits() -> Result<AuthLimits, Box<dyn std::error::Error>> {
+    let mut limits = AuthLimits::default();
+    if let Some(tries) = arg_value("--max-auth-tries") {
+        limits.max_auth_tries = tries.parse().map_err(|e| format!("--max-auth-tries: {}", e))?;
+    }
+    if let Some(seconds) = arg_value("--login-grace-time") {
+        limits.login_grace_time = Duration::from_secs(seconds.parse().map_err(|e| format!("--login-grace-time: {}", e))?);
+    }
+    Ok(limits)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let addr = "127.0.0.1:2222";
     let listener = TcpListener::bind(addr).await?;
-    let host_key = SigningKey::generate(&mut OsRng);
-    let host_key = Arc::new(host_key);
+    let host_keys = Arc::new(load_host_keys()?);
+    for key in host_keys.keys() {
+        let public_key = key.public_key();
+        println!(
+            "[Server] Host key: {} {} ({})",
+            public_key.key_type(),
+            public_key.fingerprint(),
+            public_key.fingerprint_with(FingerprintHash::Md5)
+        );
+        println!("{}", public_key.randomart(FingerprintHash::Sha256));
+    }
+    for cert in host_keys.certificates() {
+        println!(
+            "[Server] Host certificate: {} {} signed by CA {}",
+            cert.key_type(),
+            cert.key.fingerprint(),
+            cert.signature_key.fingerprint()
+        );
+    }
+
+    let mut auth_handler = load_auth_handler()?;
+    let auth_limits = load_auth_limits()?;
+    // environment= options of authorized_keys apply to sessions only with this flag
+    let permit_user_environment = std::env::args().any(|arg| arg == "--permit-user-environment");
+    // Would be shared by every connection of a server accepting more than one
+    let failure_tracker = Arc::new(FailureTracker::default());
 
     let server_handle = tokio::spawn(async move {
-        let (mut socket, _addr) = listener.accept().await.expect("Failed to accept");
-        println!("[Server] Client connected. Starting SSH handshake...");
+        let (mut socket, addr) = listener.accept().await.expect("Failed to accept");
+        if failure_tracker.is_banned(addr.ip(), now()) {
+            println!("[Server] !! {} is banned after repeated login failures. Closing.", addr.ip());
+            return;
+        }
+        println!("[Server] Client connected from {}. Starting SSH handshake...", addr);
+        auth_handler.inner.client_address = addr.ip();
+        let mut auth_attempts = AuthAttempts::new(auth_limits);
 
         // 1. Version Exchange
         let mut buffer = vec![0; 256];
-        let n = socket
-            .read(&mut buffer)
-            .await
-            .expect("Failed to read version");
+        let n = match timeout(auth_attempts.grace_remaining(), socket.read(&mut buffer)).await {
+            Ok(read) => read.expect("Failed to read version"),
+            Err(_) => {
+                println!("[Server] !! {} before the version exchange. Closing.", AuthLimitExceeded::GraceTimeExpired);
+                return;
+            }
+        };
         buffer.truncate(n);
         let mut len = buffer.len();
         while len > 0 && (buffer[len - 1] == b'\n' || buffer[len - 1] == b'\r') {
@@ -181,21 +447,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let mut read_buffer: Vec<u8> = Vec::new();
 
         // KEX State
-        let server_ephemeral_secret = EphemeralSecret::random(&mut OsRng);
-        let server_ephemeral_pk = server_ephemeral_secret.public_key();
-        let mut session_keys: Option<Vec<u8>> = None;
+        let mut kex: Option<Box<dyn KexAlgorithm>> = None;
+        let mut host_key_algorithm: Option<PublicKeyAlgorithm> = None;
+        let mut message_context = MessageContext::default();
+        let mut kex_state = KexState::default();
+        let mut session_keys: Option<SessionKeys> = None; // derived, awaiting the client's NEWKEYS
         let mut client_kex_init_payload: Option<Vec<u8>> = None;
         let mut server_kex_init_payload: Option<Vec<u8>> = None;
         let mut authenticated = false;
+        let mut restrictions = Restrictions::default();
+        let mut info_exchange: Option<InfoExchange> = None;
+        let mut banner_sent = false;
         let mut session_channel_id: Option<u32> = None;
+        let mut client_wants_ext_info = false;
 
-        // Crypto State
-        let mut client_crypto: Option<CryptoState> = None;
-        let mut server_crypto: Option<CryptoState> = None;
+        // Packet protection, per direction
+        let mut reader = PacketReader::default();
+        let mut writer = PacketWriter::default();
 
         loop {
             let mut temp_buf = [0; 1024];
-            match timeout(Duration::from_secs(10), rd.read(&mut temp_buf)).await {
+            let read_timeout = if authenticated {
+                Duration::from_secs(10)
+            } else {
+                auth_attempts.grace_remaining().min(Duration::from_secs(10))
+            };
+            match timeout(read_timeout, rd.read(&mut temp_buf)).await {
+                Err(_) if !authenticated && auth_attempts.check_grace_time().is_err() => {
+                    let exceeded = AuthLimitExceeded::GraceTimeExpired;
+                    println!("\n[Server] !! {}. Disconnecting.", exceeded);
+                    send_packet(&mut wr, &exceeded.disconnect(), &mut writer).await.ok();
+                    return;
+                }
                 Err(_) => {
                     println!("\n[Server] Timeout.");
                     break;
@@ -223,103 +506,80 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             };
 
             'packet_loop: loop {
-                let (packet_body, consumed_size) = if let Some(ref mut crypto) = client_crypto {
-                    const BLOCK_SIZE: usize = 16; // AES block size
-                    const MAC_LEN: usize = 32;
-
-                    if read_buffer.len() < BLOCK_SIZE {
-                        println!("[Server] -- Not enough data for encrypted header (have {}, need {}). Waiting for more.", read_buffer.len(), BLOCK_SIZE);
-                        break 'packet_loop;
-                    }
-
-                    // Decrypt a *copy* of the header to peek at the length, without modifying the main crypto state
-                    let mut header_block_copy = read_buffer[..BLOCK_SIZE].to_vec();
-                    xor_with_keystream(&mut header_block_copy, &crypto.key, crypto.bytes_processed);
-
-                    let packet_len =
-                        u32::from_be_bytes(header_block_copy[0..4].try_into().unwrap()) as usize;
-                    println!("[Server] -- Peeked and found packet length: {}", packet_len);
-
-                    if packet_len > 35000 { // Sanity check from RFC 4253
-                        eprintln!("[Server] -- Invalid packet length received: {}. Closing connection.", packet_len);
-                        break 'packet_loop;
-                    }
-
-                    let packet_body_len = 4 + packet_len;
-                    let total_wire_len = packet_body_len + MAC_LEN;
-
-                    if read_buffer.len() < total_wire_len {
-                        println!("[Server] -- Incomplete packet on wire (have {}, need {}). Waiting for more.", read_buffer.len(), total_wire_len);
+                let raw_packet_payload = match reader.open(&mut read_buffer) {
+                    Ok(Some(payload)) => payload,
+                    Ok(None) => {
+                        println!("[Server] -- Incomplete packet ({} bytes buffered). Waiting for more.", read_buffer.len());
                         break 'packet_loop;
                     }
-
-                    // Now we have the full packet, so we can decrypt it for real and advance the state.
-                    let packet_with_mac = &read_buffer[..total_wire_len];
-                    let decrypted_packet_body =
-                        decrypt_and_verify(crypto, 0, packet_with_mac).unwrap();
-                    (decrypted_packet_body, total_wire_len)
-                } else {
-                    if read_buffer.len() < 4 {
-                        break 'packet_loop;
-                    }
-                    let packet_len =
-                        u32::from_be_bytes(read_buffer[0..4].try_into().unwrap()) as usize;
-                    let total_len = 4 + packet_len;
-
-                    if read_buffer.len() < total_len {
-                        break 'packet_loop;
+                    Err(e) => {
+                        eprintln!("[Server] Invalid packet: {}. Closing connection.", e);
+                        return;
                     }
-                    (read_buffer[..total_len].to_vec(), total_len)
                 };
-
-                println!(
-                    "[Server] -- Processing packet body ({} bytes total): {}",
-                    packet_body.len(),
-                    format_bytes_as_repr(&packet_body)
-                );
-                read_buffer.drain(..consumed_size);
-
-                let padding_length = packet_body[4] as usize;
-                let payload_end = packet_body.len() - padding_length;
-                if 5 > payload_end || payload_end > packet_body.len() {
-                    eprintln!(
-                        "[Server] Invalid packet format: padding length {} is invalid for packet size {}",
-                        padding_length,
-                        packet_body.len()
-                    );
-                    continue 'packet_loop;
-                }
-
-                let raw_packet_payload = &packet_body[5..payload_end];
                 println!(
                     "[Server] -- Extracted payload ({} bytes): {}",
                     raw_packet_payload.len(),
-                    format_bytes_as_repr(raw_packet_payload)
+                    format_bytes_as_repr(&raw_packet_payload)
                 );
+                // A client that keeps sending (SSH_MSG_IGNORE, say) never hits the read timeout.
+                if !authenticated {
+                    if let Err(exceeded) = auth_attempts.check_grace_time() {
+                        println!("[Server] !! {}. Disconnecting.", exceeded);
+                        send_packet(&mut wr, &exceeded.disconnect(), &mut writer).await.ok();
+                        return;
+                    }
+                }
+                let number = raw_packet_payload.first().copied().unwrap_or(0);
+                if !client_message_allowed(number, reader.is_encrypted(), authenticated) {
+                    eprintln!("[Server] Message {} not allowed at this point. Disconnecting.", number);
+                    let description = format!("Message {} not allowed at this point", number);
+                    let disconnect = disconnect_message(DisconnectCode::ProtocolError, description);
+                    send_packet(&mut wr, &disconnect, &mut writer).await.ok();
+                    return;
+                }
 
-                let mut cursor = std::io::Cursor::new(raw_packet_payload);
-                match read_next_message(&mut cursor) {
+                let mut cursor = std::io::Cursor::new(raw_packet_payload.as_slice());
+                match read_next_message_in(&mut cursor, &message_context) {
                     Ok(msg) => {
                         println!("[Server] -- Parsed message: {:?}", msg);
                         match msg {
-                            SSHMsg::KexInit(_) => {
-                                client_kex_init_payload = Some(raw_packet_payload.to_vec());
+                            SSHMsg::KexInit(client_kex_init) => {
+                                client_kex_init_payload = Some(raw_packet_payload.clone());
+                                client_wants_ext_info = client_kex_init.kex_algorithms.iter().any(|name| name == "ext-info-c");
+                                let server_kex_algorithms: Vec<String> =
+                                    SUPPORTED_KEX_ALGORITHMS.iter().map(|&a| a.into()).collect();
+                                let Some(kex_name) = negotiate_algorithm(
+                                    &client_kex_init.kex_algorithms,
+                                    &server_kex_algorithms,
+                                ) else {
+                                    eprintln!("[Server] No common KEX algorithm. Closing.");
+                                    break;
+                                };
+                                println!("[Server] -- Negotiated KEX algorithm: {}", kex_name);
+                                kex = kex_algorithm_by_name(&kex_name);
+                                let server_host_key_algorithms: Vec<String> =
+                                    host_keys.algorithms().iter().map(to_ssh_name).collect();
+                                let Some((algorithm, _)) =
+                                    host_keys.select(&client_kex_init.server_host_key_algorithms)
+                                else {
+                                    eprintln!("[Server] No common host key algorithm. Closing.");
+                                    break;
+                                };
+                                println!(
+                                    "[Server] -- Negotiated host key algorithm: {}",
+                                    to_ssh_name(&algorithm)
+                                );
+                                host_key_algorithm = Some(algorithm);
+                                message_context.kex_method = from_ssh_name(&kex_name).ok();
                                 let kex_init = MsgKexInit {
                                     cookie: [0; 16],
-                                    kex_algorithms: vec!["ecdh-sha2-nistp256".into()],
-                                    server_host_key_algorithms: vec!["ssh-ed25519".into()],
-                                    encryption_algorithms_client_to_server: vec![
-                                        "aes128-ctr".into(),
-                                    ],
-                                    encryption_algorithms_server_to_client: vec![
-                                        "aes128-ctr".into(),
-                                    ],
-                                    mac_algorithms_client_to_server: vec![
-                                        "hmac-sha2-256".into(),
-                                    ],
-                                    mac_algorithms_server_to_client: vec![
-                                        "hmac-sha2-256".into(),
-                                    ],
+                                    kex_algorithms: server_kex_algorithms,
+                                    server_host_key_algorithms,
+                                    encryption_algorithms_client_to_server: SUPPORTED_CIPHERS.iter().map(|&a| a.into()).collect(),
+                                    encryption_algorithms_server_to_client: SUPPORTED_CIPHERS.iter().map(|&a| a.into()).collect(),
+                                    mac_algorithms_client_to_server: SUPPORTED_MACS.iter().map(|&a| a.into()).collect(),
+                                    mac_algorithms_server_to_client: SUPPORTED_MACS.iter().map(|&a| a.into()).collect(),
                                     compression_algorithms_client_to_server: vec!["none".into()],
                                     compression_algorithms_server_to_client: vec!["none".into()],
                                     languages_client_to_server: vec![],
@@ -330,145 +590,222 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                 let mut s_payload = Vec::new();
                                 kex_init.write_ssh(&mut s_payload).unwrap();
                                 server_kex_init_payload = Some(s_payload);
-                                send_packet(&mut wr, &kex_init, &mut server_crypto)
+                                send_packet(&mut wr, &kex_init, &mut writer)
                                     .await
                                     .unwrap();
                             }
                             SSHMsg::KexECDHInit(req) => {
-                                let client_pk =
-                                    p256::PublicKey::from_sec1_bytes(&req.q_c).unwrap();
-                                let shared = server_ephemeral_secret.diffie_hellman(&client_pk);
-                                let mut k = shared.raw_secret_bytes().to_vec();
-                                if k.get(0).map_or(false, |&b| b & 0x80 != 0) {
-                                    k.insert(0, 0);
-                                }
-
-                                let mut k_s = Vec::new();
-                                "ssh-ed25519".to_string().write_ssh(&mut k_s).unwrap();
-                                host_key
-                                    .verifying_key()
-                                    .to_bytes()
-                                    .to_vec()
-                                    .write_ssh(&mut k_s)
-                                    .unwrap();
-
-                                let mut h = Sha256::new();
-                                client_version_bytes.write_ssh(&mut h).unwrap();
-                                server_version
-                                    .trim()
-                                    .as_bytes()
-                                    .to_vec()
-                                    .write_ssh(&mut h)
-                                    .unwrap();
-                                client_kex_init_payload
-                                    .as_ref()
-                                    .unwrap()
-                                    .write_ssh(&mut h)
-                                    .unwrap();
-                                server_kex_init_payload
-                                    .as_ref()
-                                    .unwrap()
-                                    .write_ssh(&mut h)
-                                    .unwrap();
-                                k_s.write_ssh(&mut h).unwrap();
-                                req.q_c.write_ssh(&mut h).unwrap();
-                                server_ephemeral_pk
-                                    .to_sec1_bytes()
-                                    .to_vec()
-                                    .write_ssh(&mut h)
-                                    .unwrap();
-                                k.write_ssh(&mut h).unwrap();
-                                let exchange_hash = h.finalize();
-                                session_keys = Some(exchange_hash.to_vec());
-
-                                let signature = host_key.sign(&exchange_hash);
-                                let mut sig_blob = Vec::new();
-                                "ssh-ed25519".to_string().write_ssh(&mut sig_blob).unwrap();
-                                signature
-                                    .to_bytes()
-                                    .to_vec()
-                                    .write_ssh(&mut sig_blob)
-                                    .unwrap();
+                                let reply = (|| -> Result<_, String> {
+                                    let (Some(algorithm), Some(kex), Some(client_kex_init), Some(server_kex_init)) = (
+                                        host_key_algorithm.as_ref(),
+                                        kex.as_mut(),
+                                        client_kex_init_payload.clone(),
+                                        server_kex_init_payload.clone(),
+                                    ) else {
+                                        return Err("KEX_ECDH_INIT before KEXINIT".to_string());
+                                    };
+                                    let (Some(host_key), Some(public_key)) =
+                                        (host_keys.key_for(algorithm), host_keys.public_key_for(algorithm))
+                                    else {
+                                        return Err("No host key for the negotiated algorithm".to_string());
+                                    };
+                                    let k_s = public_key.to_blob();
+                                    let hash_input = ExchangeHashInput {
+                                        client_version: client_version_bytes.clone(),
+                                        server_version: server_version.trim().as_bytes().to_vec(),
+                                        client_kex_init,
+                                        server_kex_init,
+                                    };
+                                    let (q_s, output) =
+                                        kex.server_reply(&hash_input, &k_s, &req.q_c).map_err(|e| e.to_string())?;
+                                    let signature = host_key.sign(algorithm, &output.exchange_hash).map_err(|e| e.to_string())?;
+                                    Ok((q_s, output, signature, kex.hash()))
+                                })();
+                                let (q_s, output, sig_blob, kex_hash) = match reply {
+                                    Ok(reply) => reply,
+                                    Err(e) => {
+                                        eprintln!("[Server] Key exchange failed: {}. Disconnecting.", e);
+                                        let disconnect = disconnect_message(DisconnectCode::KeyExchangeFailed, e);
+                                        send_packet(&mut wr, &disconnect, &mut writer).await.ok();
+                                        return;
+                                    }
+                                };
+                                let session_id = kex_state.record_exchange(&output).clone();
+                                println!(
+                                    "[Server] -- Exchange #{} complete. Session ID: {}",
+                                    kex_state.exchanges(),
+                                    format_bytes_as_repr(session_id.as_bytes())
+                                );
+                                // aes128-ctr and hmac-sha2-256
+                                let key_lengths = KeyLengths { iv: 16, enc: 16, mac: 32 };
+                                let keys = output.derive_session_keys(kex_hash, &session_id, &key_lengths);
 
                                 let reply = MsgKexECDHReply {
-                                    k_s,
-                                    q_s: server_ephemeral_pk.to_sec1_bytes().to_vec(),
+                                    k_s: output.host_key,
+                                    q_s,
                                     signature: sig_blob,
                                 };
-                                send_packet(&mut wr, &reply, &mut server_crypto).await.unwrap();
+                                send_packet(&mut wr, &reply, &mut writer).await.unwrap();
+
+                                // The new keys apply to everything sent after our NEWKEYS.
+                                send_packet(&mut wr, &MsgNewKeys {}, &mut writer).await.unwrap();
+                                println!("[Server] -- Activating aes128-ctr/hmac-sha2-256 for server->client messages.");
+                                writer.set_cipher(PacketCipher::server_to_client(&keys).unwrap());
+                                session_keys = Some(keys);
+                                if client_wants_ext_info && kex_state.exchanges() == 1 {
+                                    let ext_info = MsgExtInfo::server_sig_algs(&auth_handler.signature_policy().algorithms());
+                                    send_packet(&mut wr, &ext_info, &mut writer).await.unwrap();
+                                }
                             }
                             SSHMsg::NewKeys(_) => {
-                                println!("[Server] !! Activating crypto for client->server messages.");
-                                client_crypto = Some(CryptoState {
-                                    key: session_keys.as_ref().unwrap().clone(),
-                                    bytes_processed: 0,
-                                });
-                                send_packet(&mut wr, &MsgNewKeys {}, &mut server_crypto)
-                                    .await
-                                    .unwrap();
-                                println!("[Server] !! Activating crypto for server->client messages.");
-                                server_crypto = Some(CryptoState {
-                                    key: session_keys.as_ref().unwrap().clone(),
-                                    bytes_processed: 0,
-                                });
+                                let Some(keys) = session_keys.take() else {
+                                    eprintln!("[Server] NEWKEYS before the key exchange completed. Disconnecting.");
+                                    let disconnect = disconnect_message(DisconnectCode::ProtocolError, "Unexpected NEWKEYS");
+                                    send_packet(&mut wr, &disconnect, &mut writer).await.ok();
+                                    return;
+                                };
+                                println!("[Server] -- Activating aes128-ctr/hmac-sha2-256 for client->server messages.");
+                                reader.set_cipher(PacketCipher::client_to_server(&keys).unwrap());
                             }
                             SSHMsg::ServiceRequest(req) => {
                                 if req.service_name == "ssh-userauth" {
                                     let accept =
                                         MsgServiceAccept { service_name: "ssh-userauth".into() };
-                                    send_packet(&mut wr, &accept, &mut server_crypto)
+                                    send_packet(&mut wr, &accept, &mut writer)
                                         .await
                                         .unwrap();
                                 } else if req.service_name == "ssh-connection" && authenticated {
                                     let accept = MsgServiceAccept {
                                         service_name: "ssh-connection".into(),
                                     };
-                                    send_packet(&mut wr, &accept, &mut server_crypto)
+                                    send_packet(&mut wr, &accept, &mut writer)
                                         .await
                                         .unwrap();
                                 }
                             }
-                            SSHMsg::UserauthRequest(req) => match req.method_name.as_str() {
-                                "none" => {
-                                    let failure = MsgUserauthFailure {
-                                        authentications_that_can_continue: vec![
-                                            "password".into()
-                                        ],
-                                        partial_success: false,
-                                    };
-                                    send_packet(&mut wr, &failure, &mut server_crypto)
-                                        .await
-                                        .unwrap();
+                            SSHMsg::UserauthRequest(_) | SSHMsg::UserauthInfoResponse(_) => {
+                                let request_started = tokio::time::Instant::now();
+                                let (user_name, method_name, reply) = match msg {
+                                    SSHMsg::UserauthRequest(req) => {
+                                        if !valid_user_name(&req.user_name) {
+                                            let exceeded = AuthLimitExceeded::IllegalUserName(req.user_name.clone());
+                                            println!("[Server] !! {}. Disconnecting.", exceeded);
+                                            failure_tracker.record_failure(addr.ip(), now());
+                                            send_packet(&mut wr, &exceeded.disconnect(), &mut writer).await.ok();
+                                            return;
+                                        }
+                                        if !banner_sent {
+                                            banner_sent = true;
+                                            if let Some(banner) = banner_for(&auth_handler, &req.user_name).await {
+                                                send_packet(&mut wr, &banner, &mut writer).await.unwrap();
+                                            }
+                                        }
+                                        message_context.auth_method = from_ssh_name(&req.method_name).ok();
+                                        // A new request abandons a keyboard-interactive exchange.
+                                        info_exchange = None;
+                                        let request = match AuthRequest::read_fields(&req.method_name, &mut cursor) {
+                                            Ok(request) => request,
+                                            Err(e) => {
+                                                eprintln!("[Server] Malformed {} request: {}. Closing.", req.method_name, e);
+                                                break;
+                                            }
+                                        };
+                                        let Some(session_id) = kex_state.session_id() else {
+                                            eprintln!("[Server] Authentication before key exchange. Closing.");
+                                            break;
+                                        };
+                                        let reply = handle_request(&auth_handler, session_id, &req, &request).await;
+                                        (req.user_name, req.method_name, reply)
+                                    }
+                                    SSHMsg::UserauthInfoResponse(response) => {
+                                        let Some(exchange) = info_exchange.take() else {
+                                            eprintln!("[Server] INFO_RESPONSE without a pending INFO_REQUEST. Closing.");
+                                            break;
+                                        };
+                                        let user_name = exchange.user_name.clone();
+                                        let reply = handle_info_response(&auth_handler, exchange, &response).await;
+                                        (user_name, "keyboard-interactive".to_string(), reply)
+                                    }
+                                    _ => unreachable!(),
+                                };
+                                let outcome = auth_attempts.record(&method_name, &reply);
+                                if outcome != AttemptOutcome::NotCounted {
+                                    let delay = auth_attempts.failure_delay(request_started.elapsed());
+                                    tokio::time::sleep(delay).await;
+                                    if failure_tracker.record_failure(addr.ip(), now()) {
+                                        println!("[Server] !! {} is now banned: {:?}", addr.ip(), failure_tracker.banned(now()));
+                                    }
                                 }
-                                "password" => {
-                                    let _has_old_pw: bool =
-                                        ReadSSH::read_ssh(&mut cursor).unwrap();
-                                    let password_str: String =
-                                        ReadSSH::read_ssh(&mut cursor).unwrap();
-
-                                    if req.user_name == "admin" && password_str == "password" {
-                                        send_packet(
-                                            &mut wr,
-                                            &MsgUserauthSuccess {},
-                                            &mut server_crypto,
-                                        )
-                                        .await
-                                        .unwrap();
+                                if outcome == AttemptOutcome::TooManyFailures {
+                                    let exceeded = AuthLimitExceeded::TooManyFailures;
+                                    println!("[Server] !! {} for {}. Disconnecting.", exceeded, user_name);
+                                    send_packet(&mut wr, &exceeded.disconnect(), &mut writer).await.ok();
+                                    return;
+                                }
+                                match reply {
+                                    AuthReply::Success(granted) => {
+                                        failure_tracker.record_success(addr.ip());
+                                        println!("[Server] -- {} authenticated with {}.", user_name, method_name);
+                                        if granted != Restrictions::default() {
+                                            println!("[Server] -- Session restricted: {:?}", granted);
+                                        }
+                                        restrictions = granted;
+                                        send_packet(&mut wr, &MsgUserauthSuccess {}, &mut writer)
+                                            .await
+                                            .unwrap();
                                         authenticated = true;
-                                    } else {
-                                        let failure = MsgUserauthFailure {
-                                            authentications_that_can_continue: vec![
-                                                "password".into(),
-                                            ],
-                                            partial_success: false,
-                                        };
-                                        send_packet(&mut wr, &failure, &mut server_crypto)
+                                        // Lets clients learn keys added during a rotation
+                                        let announcement = hostkeys::announce_host_keys(&host_keys);
+                                        send_packet(&mut wr, &announcement, &mut writer)
                                             .await
                                             .unwrap();
                                     }
+                                    AuthReply::Failure(failure) => {
+                                        send_packet(&mut wr, &failure, &mut writer).await.unwrap();
+                                    }
+                                    AuthReply::PasswdChangeReq(change) => {
+                                        send_packet(&mut wr, &change, &mut writer).await.unwrap();
+                                    }
+                                    AuthReply::PkOk(pk_ok) => {
+                                        send_packet(&mut wr, &pk_ok, &mut writer).await.unwrap();
+                                    }
+                                    AuthReply::InfoRequest(info_request, exchange) => {
+                                        send_packet(&mut wr, &info_request, &mut writer).await.unwrap();
+                                        info_exchange = Some(exchange);
+                                    }
+                                }
+                            }
+                            SSHMsg::GlobalRequest(MsgGlobalRequest {
+                                want_reply,
+                                request: GlobalRequest::HostKeysProve(requested),
+                            }) => {
+                                let (Some(session_id), Some(algorithm)) =
+                                    (kex_state.session_id(), host_key_algorithm.as_ref())
+                                else {
+                                    eprintln!("[Server] Host key proof requested before key exchange.");
+                                    if want_reply {
+                                        send_packet(&mut wr, &MsgRequestFailure {}, &mut writer).await.unwrap();
+                                    }
+                                    continue 'packet_loop;
+                                };
+                                let proof = hostkeys::prove_host_keys(&host_keys, session_id, &requested, algorithm);
+                                println!("[Server] -- Host key proof for {} keys: {:?}", requested.len(), proof.is_ok());
+                                if want_reply {
+                                    match proof {
+                                        Ok(success) => send_packet(&mut wr, &success, &mut writer).await,
+                                        Err(_) => send_packet(&mut wr, &MsgRequestFailure {}, &mut writer).await,
+                                    }
+                                    .unwrap();
+                                }
+                            }
+                            SSHMsg::GlobalRequest(req) => {
+                                println!("[Server] -- Declining global request {}", req.request.request_name());
+                                if req.want_reply {
+                                    send_packet(&mut wr, &MsgRequestFailure {}, &mut writer)
+                                        .await
+                                        .unwrap();
                                 }
-                                _ => break 'packet_loop,
-                            },
+                            }
                             SSHMsg::ChannelOpen(req) => {
                                 if req.channel_type == "session" {
                                     let server_channel_id = 0; // First channel
@@ -479,45 +816,88 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                         initial_window_size: 2097152,
                                         maximum_packet_size: 32768,
                                     };
-                                    send_packet(&mut wr, &confirmation, &mut server_crypto)
+                                    send_packet(&mut wr, &confirmation, &mut writer)
                                         .await
                                         .unwrap();
+                                } else {
+                                    let (reason_code, description) = match req.channel_type.as_str() {
+                                        "direct-tcpip" => {
+                                            let host = String::read_ssh(&mut cursor).unwrap_or_default();
+                                            let port = u32::read_ssh(&mut cursor).unwrap_or_default();
+                                            let allowed = u16::try_from(port).is_ok_and(|port| restrictions.allows_open(&host, port));
+                                            if allowed {
+                                                // Forwarding itself is not implemented
+                                                (ChannelOpenFailureReasonCode::ConnectFailed, "Forwarding not supported")
+                                            } else {
+                                                println!("[Server] -- Refusing forwarding to {}:{}", host, port);
+                                                (ChannelOpenFailureReasonCode::AdministrativelyProhibited, "Forwarding prohibited")
+                                            }
+                                        }
+                                        _ => (ChannelOpenFailureReasonCode::UnknownChannelType, "Unknown channel type"),
+                                    };
+                                    let failure = MsgChannelOpenFailure {
+                                        recipient_channel: req.sender_channel,
+                                        reason_code,
+                                        description: description.into(),
+                                        language_tag: String::new(),
+                                    };
+                                    send_packet(&mut wr, &failure, &mut writer).await.unwrap();
                                 }
                             }
                             SSHMsg::ChannelRequest(req) => {
                                 if Some(req.recipient_channel) == session_channel_id {
-                                    if req.request_type == "exec" {
+                                    if !restrictions.allows_request(&req.request_type) {
+                                        println!("[Server] -- Refusing {} request", req.request_type);
+                                        if req.want_reply {
+                                            send_packet(
+                                                &mut wr,
+                                                &MsgChannelFailure { recipient_channel: req.recipient_channel },
+                                                &mut writer,
+                                            )
+                                            .await
+                                            .unwrap();
+                                        }
+                                    } else if req.request_type == "exec" {
+                                        let requested = String::read_ssh(&mut cursor).unwrap_or_default();
+                                        let command = restrictions.command(Some(&requested)).unwrap_or_default();
+                                        let environment: Vec<String> = restrictions
+                                            .session_environment(permit_user_environment)
+                                            .iter()
+                                            .map(|(name, value)| format!("{}={} ", name, value))
+                                            .collect();
+                                        let command = format!("{}{}", environment.concat(), command);
+                                        println!("[Server] -- exec {:?}", command);
                                         if req.want_reply {
                                             send_packet(
                                                 &mut wr,
                                                 &MsgChannelSuccess {
                                                     recipient_channel: req.recipient_channel,
                                                 },
-                                                &mut server_crypto,
+                                                &mut writer,
                                             )
                                             .await
                                             .unwrap();
                                         }
-                                        let greeting = "Hello from RustySSH server!\n";
+                                        let greeting = format!("Hello from RustySSH server! ({})\n", command);
                                         let data_msg = MsgChannelData {
                                             recipient_channel: req.recipient_channel,
-                                            data: greeting.as_bytes().to_vec(),
+                                            data: greeting.into_bytes(),
                                         };
-                                        send_packet(&mut wr, &data_msg, &mut server_crypto)
+                                        send_packet(&mut wr, &data_msg, &mut writer)
                                             .await
                                             .unwrap();
 
                                         let eof_msg = MsgChannelEof {
                                             recipient_channel: req.recipient_channel,
                                         };
-                                        send_packet(&mut wr, &eof_msg, &mut server_crypto)
+                                        send_packet(&mut wr, &eof_msg, &mut writer)
                                             .await
                                             .unwrap();
 
                                         let close_msg = MsgChannelClose {
                                             recipient_channel: req.recipient_channel,
                                         };
-                                        send_packet(&mut wr, &close_msg, &mut server_crypto)
+                                        send_packet(&mut wr, &close_msg, &mut writer)
                                             .await
                                             .unwrap();
                                     }
@@ -529,7 +909,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                     let close_msg = MsgChannelClose {
                                         recipient_channel: req.recipient_channel,
                                     };
-                                    send_packet(&mut wr, &close_msg, &mut server_crypto)
+                                    send_packet(&mut wr, &close_msg, &mut writer)
                                         .await
                                         .unwrap();
                                     break;
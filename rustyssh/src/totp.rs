@@ -0,0 +1,210 @@
+use crate::msg::InfoPrompt;
+use crate::userauth::AuthResult;
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Time-based one-time passwords (RFC 6238) with HMAC-SHA1, as generated by common
+// authenticator apps, checked in a keyboard-interactive round.
+//
+// Secrets files hold one user per line: the user name and the base32 secret, e.g.
+// "alice JBSWY3DPEHPK3PXP". Blank lines and lines starting with '#' are ignored.
+
+/// How codes are generated and how much clock difference is tolerated.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TotpConfig {
+    pub digits: u32, // 6 to 8, checked by `TotpVerifier::new`
+    pub period: u64, // seconds per time step, at least one
+    pub skew: u64,   // time steps accepted before and after the current one
+}
+
+impl Default for TotpConfig {
+    fn default() -> Self {
+        TotpConfig { digits: 6, period: 30, skew: 1 }
+    }
+}
+
+/// Why a `TotpConfig` cannot be used.
+#[derive(Debug, PartialEq, Clone, thiserror::Error)]
+pub enum TotpConfigError {
+    #[error("TOTP codes must have 6 to 8 digits, not {0}")]
+    Digits(u32),
+    #[error("TOTP period must be at least one second")]
+    Period,
+}
+
+impl TotpConfig {
+    pub fn validate(&self) -> Result<(), TotpConfigError> {
+        if !(6..=8).contains(&self.digits) {
+            return Err(TotpConfigError::Digits(self.digits));
+        }
+        if self.period == 0 {
+            return Err(TotpConfigError::Period);
+        }
+        Ok(())
+    }
+}
+
+/// The HOTP value (RFC 4226 §5.3) of `counter`.
+pub fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    // Dynamic truncation
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+    // Values have at most 10 digits, so more leave them whole.
+    10u32.checked_pow(digits).map_or(binary, |modulus| binary % modulus)
+}
+
+/// The code for `time` (seconds since the epoch), zero-padded to `config.digits`.
+pub fn totp(secret: &[u8], time: u64, config: &TotpConfig) -> String {
+    let code = hotp(secret, time / config.period, config.digits);
+    format!("{:0width$}", code, width = config.digits as usize)
+}
+
+/// Decodes RFC 4648 base32, ignoring case, spaces and padding, as authenticator apps
+/// display secrets.
+pub fn decode_base32(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.chars().filter(|&c| c != ' ' && c != '=') {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+/// Per-user TOTP secrets.
+#[derive(Debug, Default, Clone)]
+pub struct TotpSecrets {
+    secrets: HashMap<String, Vec<u8>>,
+}
+
+impl TotpSecrets {
+    /// Parses a secrets file. Lines without a valid secret are skipped.
+    pub fn parse(text: &str) -> Self {
+        let secrets = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let (user_name, secret) = line.split_once(char::is_whitespace)?;
+                let secret = decode_base32(secret.trim()).filter(|secret| !secret.is_empty())?;
+                Some((user_name.to_string(), secret))
+            })
+            .collect();
+        TotpSecrets { secrets }
+    }
+
+    /// Reads a secrets file, which must not be accessible to other users. A missing file
+    /// has no secrets.
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        match std::fs::metadata(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+            #[cfg(unix)]
+            Ok(metadata) => {
+                use std::os::unix::fs::PermissionsExt;
+                if metadata.permissions().mode() & 0o077 != 0 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::PermissionDenied,
+                        format!("{} is accessible by other users", path.display()),
+                    ));
+                }
+            }
+            #[cfg(not(unix))]
+            Ok(_) => {}
+        }
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    pub fn get(&self, user_name: &str) -> Option<&[u8]> {
+        self.secrets.get(user_name).map(Vec::as_slice)
+    }
+
+    pub fn len(&self) -> usize {
+        self.secrets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+}
+
+/// Checks TOTP codes as a keyboard-interactive backend. Each code is accepted once: a
+/// user's later codes must be from a later time step than the last one accepted. Share
+/// one verifier between connections so that this holds across them.
+#[derive(Debug)]
+pub struct TotpVerifier {
+    secrets: TotpSecrets,
+    config: TotpConfig,
+    last_used: Mutex<HashMap<String, u64>>, // time step of the last accepted code per user
+}
+
+impl TotpVerifier {
+    pub fn new(secrets: TotpSecrets, config: TotpConfig) -> Result<Self, TotpConfigError> {
+        config.validate()?;
+        Ok(TotpVerifier { secrets, config, last_used: Mutex::new(HashMap::new()) })
+    }
+
+    pub fn has_secret(&self, user_name: &str) -> bool {
+        self.secrets.get(user_name).is_some()
+    }
+
+    /// Whether `code` is valid for `user_name` at `now` (seconds since the epoch) and has
+    /// not been used before.
+    pub fn verify(&self, user_name: &str, code: &str, now: u64) -> bool {
+        let Some(secret) = self.secrets.get(user_name) else {
+            return false;
+        };
+        if code.len() != self.config.digits as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return false;
+        }
+        let Ok(code) = code.parse::<u32>() else {
+            return false;
+        };
+        let current = now / self.config.period;
+        let mut last_used = self.last_used.lock().unwrap();
+        let unused = |step: u64| last_used.get(user_name).is_none_or(|&last| step > last);
+        let matching = (current.saturating_sub(self.config.skew)..=current.saturating_add(self.config.skew))
+            .find(|&step| unused(step) && hotp(secret, step, self.config.digits) == code);
+        match matching {
+            Some(step) => {
+                last_used.insert(user_name.to_string(), step);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The keyboard-interactive round asking for a code.
+    pub fn info_request(&self) -> AuthResult {
+        AuthResult::InfoRequest {
+            name: String::new(),
+            instruction: String::new(),
+            prompts: vec![InfoPrompt { prompt: "Verification code: ".to_string(), echo: false }],
+        }
+    }
+
+    /// Checks the answer to `info_request`, the last round of `responses`.
+    pub fn verify_responses(&self, user_name: &str, responses: &[Vec<String>], now: u64) -> bool {
+        match responses.last().map(Vec::as_slice) {
+            Some([code]) => self.verify(user_name, code.trim(), now),
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use crate::kex::SessionKeys;
+
+use aes::Aes128;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+// The binary packet protocol (RFC 4253 §6): uint32 packet_length, byte padding_length,
+// the payload and random padding, encrypted as a whole and followed by a MAC over the
+// sequence number and the unencrypted packet.
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+
+/// Largest packet_length accepted from the peer (RFC 4253 §6.1 requires at least 35000).
+pub const MAX_PACKET_LENGTH: usize = 35000;
+
+/// Cipher block size before NEWKEYS, when packets are sent in the clear.
+const CLEAR_BLOCK_SIZE: usize = 8;
+
+/// Encryption methods implemented by this crate, in order of preference.
+pub const SUPPORTED_CIPHERS: &[&str] = &["aes128-ctr"];
+
+/// MAC methods implemented by this crate, in order of preference.
+pub const SUPPORTED_MACS: &[&str] = &["hmac-sha2-256"];
+
+/// Whether a server may act on a message numbered `number` from the client. Messages of
+/// the authentication protocol (50 to 79) need the first key exchange to be complete, and
+/// connection protocol messages (80 and up) a successful login as well (RFC 4252 §6).
+pub fn client_message_allowed(number: u8, keys_in_use: bool, authenticated: bool) -> bool {
+    match number {
+        1..=49 => true,
+        50..=79 => keys_in_use,
+        _ => keys_in_use && authenticated,
+    }
+}
+
+fn invalid_data(message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.into())
+}
+
+/// Frames a payload with at least four bytes of random padding, so that the packet is a
+/// multiple of `block_size`.
+pub fn frame_packet(payload: &[u8], block_size: usize) -> Vec<u8> {
+    let mut padding_len = block_size - (4 + 1 + payload.len()) % block_size;
+    if padding_len < 4 {
+        padding_len += block_size;
+    }
+
+    let packet_length = 1 + payload.len() + padding_len;
+    let mut packet = Vec::with_capacity(4 + packet_length);
+    packet.extend_from_slice(&(packet_length as u32).to_be_bytes());
+    packet.push(padding_len as u8);
+    packet.extend_from_slice(payload);
+    let mut padding = vec![0u8; padding_len];
+    rand::thread_rng().fill_bytes(&mut padding);
+    packet.extend_from_slice(&padding);
+    packet
+}
+
+/// The payload of an unencrypted packet, including its length field.
+pub fn packet_payload(packet: &[u8]) -> std::io::Result<&[u8]> {
+    let padding_len = *packet.get(4).ok_or_else(|| invalid_data("Packet too short"))? as usize;
+    if 5 + padding_len > packet.len() {
+        return Err(invalid_data(format!(
+            "Padding length {} is invalid for packet size {}",
+            padding_len,
+            packet.len()
+        )));
+    }
+    Ok(&packet[5..packet.len() - padding_len])
+}
+
+/// One direction of aes128-ctr with hmac-sha2-256 (RFC 4344, RFC 6668).
+/// The keystream continues across packets, so a direction needs one instance for its lifetime.
+pub struct PacketCipher {
+    cipher: Aes128Ctr,
+    mac_key: Vec<u8>,
+}
+
+impl PacketCipher {
+    pub const BLOCK_SIZE: usize = 16;
+    pub const MAC_LEN: usize = 32;
+
+    pub fn new(key: &[u8], iv: &[u8], mac_key: &[u8]) -> std::io::Result<Self> {
+        let cipher = Aes128Ctr::new_from_slices(key, iv)
+            .map_err(|_| invalid_data("Invalid aes128-ctr key or IV length"))?;
+        Ok(PacketCipher { cipher, mac_key: mac_key.to_vec() })
+    }
+
+    pub fn client_to_server(keys: &SessionKeys) -> std::io::Result<Self> {
+        Self::new(&keys.enc_client_to_server, &keys.iv_client_to_server, &keys.mac_client_to_server)
+    }
+
+    pub fn server_to_client(keys: &SessionKeys) -> std::io::Result<Self> {
+        Self::new(&keys.enc_server_to_client, &keys.iv_server_to_client, &keys.mac_server_to_client)
+    }
+
+    fn mac(&self, sequence: u32, packet: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.mac_key).expect("HMAC accepts any key length");
+        mac.update(&sequence.to_be_bytes());
+        mac.update(packet);
+        mac
+    }
+
+    /// Encrypts a framed packet and appends its MAC.
+    pub fn seal(&mut self, sequence: u32, packet: &[u8]) -> Vec<u8> {
+        let tag = self.mac(sequence, packet).finalize().into_bytes();
+        let mut sealed = packet.to_vec();
+        self.cipher.apply_keystream(&mut sealed);
+        sealed.extend_from_slice(&tag);
+        sealed
+    }
+
+    /// Decrypts and authenticates the packet at the start of `buffer`. Returns the
+    /// decrypted packet and the number of bytes it took on the wire, or `None` if the
+    /// buffer does not hold the whole packet yet. The keystream only advances once the
+    /// packet is complete.
+    pub fn open(&mut self, sequence: u32, buffer: &[u8]) -> std::io::Result<Option<(Vec<u8>, usize)>> {
+        if buffer.len() < Self::BLOCK_SIZE {
+            return Ok(None);
+        }
+        let mut first_block = buffer[..Self::BLOCK_SIZE].to_vec();
+        self.cipher.clone().apply_keystream(&mut first_block);
+        let packet_length = u32::from_be_bytes(first_block[..4].try_into().unwrap()) as usize;
+        if packet_length > MAX_PACKET_LENGTH || !(4 + packet_length).is_multiple_of(Self::BLOCK_SIZE) {
+            return Err(invalid_data(format!("Invalid packet length {}", packet_length)));
+        }
+
+        let wire_len = 4 + packet_length + Self::MAC_LEN;
+        if buffer.len() < wire_len {
+            return Ok(None);
+        }
+        let mut packet = buffer[..4 + packet_length].to_vec();
+        self.cipher.apply_keystream(&mut packet);
+        self.mac(sequence, &packet)
+            .verify_slice(&buffer[4 + packet_length..wire_len])
+            .map_err(|_| invalid_data("MAC verification failed"))?;
+        Ok(Some((packet, wire_len)))
+    }
+}
+
+/// The sending half of a connection: frames payloads, counts sequence numbers and
+/// encrypts once keys are in use.
+#[derive(Default)]
+pub struct PacketWriter {
+    sequence: u32,
+    cipher: Option<PacketCipher>,
+}
+
+impl PacketWriter {
+    /// Switches to new keys, right after sending SSH_MSG_NEWKEYS.
+    pub fn set_cipher(&mut self, cipher: PacketCipher) {
+        self.cipher = Some(cipher);
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Turns a message payload into the bytes to put on the wire.
+    pub fn seal(&mut self, payload: &[u8]) -> Vec<u8> {
+        let sequence = self.sequence;
+        // The sequence number wraps around after 2^32 packets and is never reset.
+        self.sequence = self.sequence.wrapping_add(1);
+        match &mut self.cipher {
+            Some(cipher) => cipher.seal(sequence, &frame_packet(payload, PacketCipher::BLOCK_SIZE)),
+            None => frame_packet(payload, CLEAR_BLOCK_SIZE),
+        }
+    }
+}
+
+/// The receiving half of a connection, the counterpart of `PacketWriter`.
+#[derive(Default)]
+pub struct PacketReader {
+    sequence: u32,
+    cipher: Option<PacketCipher>,
+}
+
+impl PacketReader {
+    /// Switches to new keys, right after receiving SSH_MSG_NEWKEYS.
+    pub fn set_cipher(&mut self, cipher: PacketCipher) {
+        self.cipher = Some(cipher);
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Removes the next complete packet from `buffer` and returns its payload, or `None`
+    /// if more data has to be read first.
+    pub fn open(&mut self, buffer: &mut Vec<u8>) -> std::io::Result<Option<Vec<u8>>> {
+        let (packet, consumed) = match &mut self.cipher {
+            Some(cipher) => match cipher.open(self.sequence, buffer)? {
+                Some(opened) => opened,
+                None => return Ok(None),
+            },
+            None => {
+                if buffer.len() < 4 {
+                    return Ok(None);
+                }
+                let packet_length = u32::from_be_bytes(buffer[..4].try_into().unwrap()) as usize;
+                if packet_length > MAX_PACKET_LENGTH {
+                    return Err(invalid_data(format!("Invalid packet length {}", packet_length)));
+                }
+                if buffer.len() < 4 + packet_length {
+                    return Ok(None);
+                }
+                (buffer[..4 + packet_length].to_vec(), 4 + packet_length)
+            }
+        };
+        buffer.drain(..consumed);
+        self.sequence = self.sequence.wrapping_add(1);
+        packet_payload(&packet).map(|payload| Some(payload.to_vec()))
+    }
+}
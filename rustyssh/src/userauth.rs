@@ -0,0 +1,680 @@
+use crate::api::{from_ssh_name, to_ssh_name, ReadSSH, WriteSSH};
+use crate::kex::SessionId;
+use crate::keys::{KeyError, PrivateKey, PublicKey, SignaturePolicy};
+use crate::msg::{
+    InfoPrompt, MsgUserauthBanner, MsgUserauthFailure, MsgUserauthInfoRequest, MsgUserauthInfoResponse,
+    MsgUserauthPasswdChangeReq, MsgUserauthPkOk, MsgUserauthRequest, PublicKeyAlgorithm,
+};
+use std::future::Future;
+
+/// The data a client signs for "publickey" authentication (RFC 4252 §7):
+/// the session identifier followed by the request up to and including the public key blob.
+pub fn publickey_signature_data(
+    session_id: &SessionId,
+    user_name: &str,
+    service_name: &str,
+    algorithm_name: &str,
+    public_key_blob: &[u8],
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    write_request_prefix(&mut data, session_id, user_name, service_name, "publickey");
+    true.write_ssh(&mut data).unwrap();
+    algorithm_name.to_string().write_ssh(&mut data).unwrap();
+    public_key_blob.to_vec().write_ssh(&mut data).unwrap();
+    data
+}
+
+/// The data a client host signs for "hostbased" authentication (RFC 4252 §9).
+pub fn hostbased_signature_data(
+    session_id: &SessionId,
+    user_name: &str,
+    service_name: &str,
+    algorithm_name: &str,
+    host_key_blob: &[u8],
+    client_host_name: &str,
+    client_user_name: &str,
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    write_request_prefix(&mut data, session_id, user_name, service_name, "hostbased");
+    algorithm_name.to_string().write_ssh(&mut data).unwrap();
+    host_key_blob.to_vec().write_ssh(&mut data).unwrap();
+    client_host_name.to_string().write_ssh(&mut data).unwrap();
+    client_user_name.to_string().write_ssh(&mut data).unwrap();
+    data
+}
+
+/// Parses the key of a publickey or hostbased request and checks that the requested
+/// algorithm belongs to it, e.g. that "rsa-sha2-256" comes with an RSA key.
+pub fn check_key_algorithm(algorithm_name: &str, key_blob: &[u8], policy: &SignaturePolicy) -> Result<PublicKey, KeyError> {
+    let key = PublicKey::from_blob(key_blob)?;
+    let algorithm: PublicKeyAlgorithm = from_ssh_name(algorithm_name)?;
+    if !key.signature_algorithms(policy).contains(&algorithm) {
+        return Err(KeyError::Signature(format!(
+            "{} is not an acceptable algorithm for a {} key",
+            algorithm_name,
+            key.key_type()
+        )));
+    }
+    Ok(key)
+}
+
+/// Verifies the signature of a request made with `algorithm_name`. The signature must use
+/// that algorithm, or its plain counterpart for a certificate.
+pub fn verify_request_signature(
+    key: &PublicKey,
+    algorithm_name: &str,
+    data: &[u8],
+    signature_blob: &[u8],
+    policy: &SignaturePolicy,
+) -> Result<(), KeyError> {
+    let requested = from_ssh_name::<PublicKeyAlgorithm>(algorithm_name)?.signature_algorithm();
+    let signed_with = PublicKeyAlgorithm::read_ssh(signature_blob)?;
+    if signed_with != requested {
+        return Err(KeyError::Signature(format!(
+            "Request for {} signed with {:?}",
+            algorithm_name, signed_with
+        )));
+    }
+    key.verify(data, signature_blob, policy)
+}
+
+/// Client side: a hostbased request for `user_name`, signed with the client host's key.
+/// `client_host_name` should be the fully qualified name the server can resolve to the
+/// client's address.
+pub fn sign_hostbased_request(
+    session_id: &SessionId,
+    header: &MsgUserauthRequest,
+    host_key: &PrivateKey,
+    algorithm: &PublicKeyAlgorithm,
+    client_host_name: &str,
+    client_user_name: &str,
+) -> Result<AuthRequest, KeyError> {
+    let algorithm_name = to_ssh_name(algorithm);
+    let host_key_blob = host_key.public_key().to_blob();
+    let data = hostbased_signature_data(
+        session_id,
+        &header.user_name,
+        &header.service_name,
+        &algorithm_name,
+        &host_key_blob,
+        client_host_name,
+        client_user_name,
+    );
+    let signature = host_key.sign(algorithm, &data)?;
+    Ok(AuthRequest::Hostbased {
+        algorithm: algorithm_name,
+        host_key_blob,
+        client_host_name: client_host_name.to_string(),
+        client_user_name: client_user_name.to_string(),
+        signature,
+    })
+}
+
+fn write_request_prefix(
+    data: &mut Vec<u8>,
+    session_id: &SessionId,
+    user_name: &str,
+    service_name: &str,
+    method_name: &str,
+) {
+    session_id.as_bytes().to_vec().write_ssh(data).unwrap();
+    MsgUserauthRequest {
+        user_name: user_name.to_string(),
+        service_name: service_name.to_string(),
+        method_name: method_name.to_string(),
+    }
+    .write_ssh(data)
+    .unwrap();
+}
+
+/// The method-specific fields of an SSH_MSG_USERAUTH_REQUEST (RFC 4252 §§5-9, RFC 4256 §3.1).
+/// Passwords are left out of the `Debug` output.
+#[derive(PartialEq, Clone)]
+pub enum AuthRequest {
+    None,
+    Password {
+        password: String,
+        new_password: Option<String>, // set when the client answers a change request
+    },
+    PublicKey {
+        algorithm: String,
+        key_blob: Vec<u8>,
+        signature: Option<Vec<u8>>, // absent when only asking whether the key is acceptable
+    },
+    KeyboardInteractive {
+        submethods: Vec<String>,
+    },
+    Hostbased {
+        algorithm: String,
+        host_key_blob: Vec<u8>,
+        client_host_name: String,
+        client_user_name: String,
+        signature: Vec<u8>,
+    },
+    /// A method this crate does not know; it always fails.
+    Unsupported(String),
+}
+
+impl std::fmt::Debug for AuthRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthRequest::None => f.write_str("None"),
+            AuthRequest::Password { new_password, .. } => f
+                .debug_struct("Password")
+                .field("password", &format_args!("<redacted>"))
+                .field("new_password", &new_password.as_ref().map(|_| format_args!("<redacted>")))
+                .finish(),
+            AuthRequest::PublicKey { algorithm, key_blob, signature } => f
+                .debug_struct("PublicKey")
+                .field("algorithm", algorithm)
+                .field("key_blob", key_blob)
+                .field("signature", signature)
+                .finish(),
+            AuthRequest::KeyboardInteractive { submethods } => {
+                f.debug_struct("KeyboardInteractive").field("submethods", submethods).finish()
+            }
+            AuthRequest::Hostbased { algorithm, host_key_blob, client_host_name, client_user_name, signature } => f
+                .debug_struct("Hostbased")
+                .field("algorithm", algorithm)
+                .field("host_key_blob", host_key_blob)
+                .field("client_host_name", client_host_name)
+                .field("client_user_name", client_user_name)
+                .field("signature", signature)
+                .finish(),
+            AuthRequest::Unsupported(method_name) => f.debug_tuple("Unsupported").field(method_name).finish(),
+        }
+    }
+}
+
+impl AuthRequest {
+    /// Reads the fields that follow the method name in the request.
+    pub fn read_fields<R: std::io::Read>(method_name: &str, mut reader: R) -> Result<Self, std::io::Error> {
+        Ok(match method_name {
+            "none" => AuthRequest::None,
+            "password" => {
+                let changing = bool::read_ssh(&mut reader)?;
+                let password = String::read_ssh(&mut reader)?;
+                let new_password = if changing { Some(String::read_ssh(&mut reader)?) } else { None };
+                AuthRequest::Password { password, new_password }
+            }
+            "publickey" => {
+                let signed = bool::read_ssh(&mut reader)?;
+                let algorithm = String::read_ssh(&mut reader)?;
+                let key_blob = Vec::<u8>::read_ssh(&mut reader)?;
+                let signature = if signed { Some(Vec::<u8>::read_ssh(&mut reader)?) } else { None };
+                AuthRequest::PublicKey { algorithm, key_blob, signature }
+            }
+            "keyboard-interactive" => {
+                let _language_tag = String::read_ssh(&mut reader)?; // deprecated, always empty
+                let submethods = String::read_ssh(&mut reader)?;
+                let submethods = submethods.split(',').filter(|name| !name.is_empty()).map(str::to_string).collect();
+                AuthRequest::KeyboardInteractive { submethods }
+            }
+            "hostbased" => AuthRequest::Hostbased {
+                algorithm: String::read_ssh(&mut reader)?,
+                host_key_blob: Vec::<u8>::read_ssh(&mut reader)?,
+                client_host_name: String::read_ssh(&mut reader)?,
+                client_user_name: String::read_ssh(&mut reader)?,
+                signature: Vec::<u8>::read_ssh(&mut reader)?,
+            },
+            other => AuthRequest::Unsupported(other.to_string()),
+        })
+    }
+
+    /// Writes the fields that follow the method name, the reverse of `read_fields`.
+    pub fn write_fields<W: std::io::Write>(&self, mut writer: W) -> Result<(), std::io::Error> {
+        match self {
+            AuthRequest::None | AuthRequest::Unsupported(_) => {}
+            AuthRequest::Password { password, new_password } => {
+                new_password.is_some().write_ssh(&mut writer)?;
+                password.write_ssh(&mut writer)?;
+                if let Some(new_password) = new_password {
+                    new_password.write_ssh(&mut writer)?;
+                }
+            }
+            AuthRequest::PublicKey { algorithm, key_blob, signature } => {
+                signature.is_some().write_ssh(&mut writer)?;
+                algorithm.write_ssh(&mut writer)?;
+                key_blob.write_ssh(&mut writer)?;
+                if let Some(signature) = signature {
+                    signature.write_ssh(&mut writer)?;
+                }
+            }
+            AuthRequest::KeyboardInteractive { submethods } => {
+                String::new().write_ssh(&mut writer)?; // language tag
+                submethods.join(",").write_ssh(&mut writer)?;
+            }
+            AuthRequest::Hostbased { algorithm, host_key_blob, client_host_name, client_user_name, signature } => {
+                algorithm.write_ssh(&mut writer)?;
+                host_key_blob.write_ssh(&mut writer)?;
+                client_host_name.write_ssh(&mut writer)?;
+                client_user_name.write_ssh(&mut writer)?;
+                signature.write_ssh(&mut writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn method_name(&self) -> &str {
+        match self {
+            AuthRequest::None => "none",
+            AuthRequest::Password { .. } => "password",
+            AuthRequest::PublicKey { .. } => "publickey",
+            AuthRequest::KeyboardInteractive { .. } => "keyboard-interactive",
+            AuthRequest::Hostbased { .. } => "hostbased",
+            AuthRequest::Unsupported(name) => name,
+        }
+    }
+}
+
+/// What an authenticated session may do, from authorized_keys options or certificate
+/// options and extensions. The default allows everything.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Restrictions {
+    /// Run instead of whatever the client asks for.
+    pub force_command: Option<String>,
+    pub pty: bool,
+    pub port_forwarding: bool,
+    pub agent_forwarding: bool,
+    pub x11_forwarding: bool,
+    pub user_rc: bool,
+    /// Destinations of direct-tcpip channels as (host, port), where "*" matches any host
+    /// and a missing port any port. Empty allows every destination.
+    pub permit_open: Vec<(String, Option<u16>)>,
+    /// Variables to set for the session, as NAME=value. Only applied when the server opts
+    /// in, see `session_environment`.
+    pub environment: Vec<(String, String)>,
+}
+
+impl Default for Restrictions {
+    fn default() -> Self {
+        Restrictions {
+            force_command: None,
+            pty: true,
+            port_forwarding: true,
+            agent_forwarding: true,
+            x11_forwarding: true,
+            user_rc: true,
+            permit_open: Vec::new(),
+            environment: Vec::new(),
+        }
+    }
+}
+
+impl Restrictions {
+    /// Everything off, as the authorized_keys `restrict` option does.
+    pub fn restricted() -> Self {
+        Restrictions {
+            pty: false,
+            port_forwarding: false,
+            agent_forwarding: false,
+            x11_forwarding: false,
+            user_rc: false,
+            ..Self::default()
+        }
+    }
+
+    /// Whether a direct-tcpip channel to `host` and `port` may be opened.
+    pub fn allows_open(&self, host: &str, port: u16) -> bool {
+        self.port_forwarding
+            && (self.permit_open.is_empty()
+                || self.permit_open.iter().any(|(allowed_host, allowed_port)| {
+                    (allowed_host == "*" || allowed_host.eq_ignore_ascii_case(host))
+                        && allowed_port.is_none_or(|allowed_port| allowed_port == port)
+                }))
+    }
+
+    /// Whether a session channel request such as "pty-req" is allowed.
+    pub fn allows_request(&self, request_type: &str) -> bool {
+        match request_type {
+            "pty-req" => self.pty,
+            "x11-req" => self.x11_forwarding,
+            "auth-agent-req@openssh.com" => self.agent_forwarding,
+            _ => true,
+        }
+    }
+
+    /// The command to run for an exec (`Some`) or shell (`None`) request.
+    pub fn command<'a>(&'a self, requested: Option<&'a str>) -> Option<&'a str> {
+        self.force_command.as_deref().or(requested)
+    }
+
+    /// The variables to set for an exec or shell session. Like sshd's PermitUserEnvironment,
+    /// `environment` is ignored unless `permit_user_environment`, since users able to edit
+    /// their authorized_keys could otherwise change how a forced command runs (LD_PRELOAD).
+    pub fn session_environment(&self, permit_user_environment: bool) -> &[(String, String)] {
+        if permit_user_environment { &self.environment } else { &[] }
+    }
+
+    /// Restrictions from two sources at once, such as a certificate and the authorized_keys
+    /// line of its authority: only what both allow. `None` if they force different commands.
+    pub fn intersect(&self, other: &Restrictions) -> Option<Restrictions> {
+        let force_command = match (&self.force_command, &other.force_command) {
+            (Some(a), Some(b)) if a != b => return None,
+            (a, b) => a.clone().or_else(|| b.clone()),
+        };
+        let (permit_open, disjoint) = match (self.permit_open.is_empty(), other.permit_open.is_empty()) {
+            (_, true) => (self.permit_open.clone(), false),
+            (true, false) => (other.permit_open.clone(), false),
+            (false, false) => {
+                let common: Vec<_> =
+                    self.permit_open.iter().filter(|open| other.permit_open.contains(open)).cloned().collect();
+                let disjoint = common.is_empty();
+                (common, disjoint)
+            }
+        };
+        // An empty list allows everything, so two lists that share nothing forbid forwarding.
+        let port_forwarding = self.port_forwarding && other.port_forwarding && !disjoint;
+        Some(Restrictions {
+            force_command,
+            pty: self.pty && other.pty,
+            port_forwarding,
+            agent_forwarding: self.agent_forwarding && other.agent_forwarding,
+            x11_forwarding: self.x11_forwarding && other.x11_forwarding,
+            user_rc: self.user_rc && other.user_rc,
+            permit_open,
+            environment: self.environment.iter().chain(&other.environment).cloned().collect(),
+        })
+    }
+}
+
+/// The decision of an `AuthHandler` on one authentication attempt.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum AuthResult {
+    Accept,
+    /// Accept, but limit what the session may do.
+    Restricted(Restrictions),
+    Reject,
+    /// The method succeeded, but further methods are required (RFC 4252 §5.1).
+    PartialSuccess,
+    /// The password was right but has expired; the client must choose a new one (RFC 4252 §8).
+    /// Only meaningful for the password method.
+    ChangePassword { prompt: String },
+    /// Ask the client another round of questions (RFC 4256 §3.2). Only meaningful for the
+    /// keyboard-interactive method.
+    InfoRequest { name: String, instruction: String, prompts: Vec<InfoPrompt> },
+}
+
+/// Decides who may log in. Every method is rejected unless the handler implements it and
+/// lists it in `methods`.
+pub trait AuthHandler: Send + Sync {
+    /// The methods offered to `user_name` in SSH_MSG_USERAUTH_FAILURE, most preferred first,
+    /// such as "publickey" or "password". "none" is never listed (RFC 4252 §5.2).
+    fn methods(&self, user_name: &str) -> Vec<&'static str>;
+
+    /// Text to show `user_name` before authentication completes, such as a legal notice
+    /// (RFC 4252 §5.4). Servers ask once per connection, on the first request.
+    fn banner(&self, _user_name: &str) -> impl Future<Output = Option<String>> + Send {
+        async { None }
+    }
+
+    fn auth_none(&self, _user_name: &str) -> impl Future<Output = AuthResult> + Send {
+        async { AuthResult::Reject }
+    }
+
+    /// `new_password` is set when the client answers a change request, or changes its
+    /// password unasked (RFC 4252 §8). To force a change, return `AuthResult::ChangePassword`
+    /// for the old password alone; once `password` is right, accepting the new one completes
+    /// the login, and returning `ChangePassword` again asks for a different one.
+    fn auth_password(
+        &self,
+        _user_name: &str,
+        _password: &str,
+        _new_password: Option<&str>,
+    ) -> impl Future<Output = AuthResult> + Send {
+        async { AuthResult::Reject }
+    }
+
+    /// Signature algorithms accepted from clients.
+    fn signature_policy(&self) -> SignaturePolicy {
+        SignaturePolicy::default()
+    }
+
+    /// Whether `key` may log in as `user_name`. Only asked once the client has proven it
+    /// holds the key by signing the request.
+    fn auth_publickey(&self, _user_name: &str, _key: &PublicKey) -> impl Future<Output = AuthResult> + Send {
+        async { AuthResult::Reject }
+    }
+
+    /// Whether a key the client only offers is worth signing with, answered with
+    /// SSH_MSG_USERAUTH_PK_OK (RFC 4252 §7). Asks `auth_publickey` by default; handlers that
+    /// remember successful attempts, such as a first factor, must not count this one.
+    fn publickey_acceptable(&self, user_name: &str, key: &PublicKey) -> impl Future<Output = bool> + Send {
+        async move {
+            matches!(
+                self.auth_publickey(user_name, key).await,
+                AuthResult::Accept | AuthResult::Restricted(_) | AuthResult::PartialSuccess
+            )
+        }
+    }
+
+    /// Starts a keyboard-interactive exchange, usually by returning `AuthResult::InfoRequest`.
+    /// `submethods` are hints from the client such as "pam", often empty.
+    fn auth_keyboard_interactive(
+        &self,
+        _user_name: &str,
+        _submethods: &[String],
+    ) -> impl Future<Output = AuthResult> + Send {
+        async { AuthResult::Reject }
+    }
+
+    /// Continues a keyboard-interactive exchange with the client's answers to every round so
+    /// far, the latest last. Each round has one answer per prompt.
+    fn auth_info_response(&self, _user_name: &str, _responses: &[Vec<String>]) -> impl Future<Output = AuthResult> + Send {
+        async { AuthResult::Reject }
+    }
+
+    /// Whether `client_user_name` on `client_host_name` may log in as `user_name`. Only
+    /// asked once the request is signed by `host_key`; whether that key belongs to the host,
+    /// and the host to the connecting address, is up to the handler (see
+    /// `hostbased::HostbasedPolicy`).
+    fn auth_hostbased(
+        &self,
+        _user_name: &str,
+        _client_host_name: &str,
+        _client_user_name: &str,
+        _host_key: &PublicKey,
+    ) -> impl Future<Output = AuthResult> + Send {
+        async { AuthResult::Reject }
+    }
+}
+
+/// The server's answer to a userauth request.
+#[derive(Debug, PartialEq)]
+pub enum AuthReply {
+    Success(Restrictions),
+    Failure(MsgUserauthFailure),
+    PasswdChangeReq(MsgUserauthPasswdChangeReq),
+    /// The key of an unsigned publickey request is acceptable (RFC 4252 §7).
+    PkOk(MsgUserauthPkOk),
+    /// Questions for the client; its SSH_MSG_USERAUTH_INFO_RESPONSE goes to
+    /// `handle_info_response` together with the exchange.
+    InfoRequest(MsgUserauthInfoRequest, InfoExchange),
+}
+
+/// A keyboard-interactive exchange waiting for the client's answers. A new userauth
+/// request abandons it (RFC 4256 §3.4).
+#[derive(PartialEq, Clone)]
+pub struct InfoExchange {
+    pub user_name: String,
+    pub service_name: String,
+    pub prompts: usize,              // number of answers expected in the next response
+    pub responses: Vec<Vec<String>>, // answers to the earlier rounds
+}
+
+impl std::fmt::Debug for InfoExchange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InfoExchange")
+            .field("user_name", &self.user_name)
+            .field("service_name", &self.service_name)
+            .field("prompts", &self.prompts)
+            .field("responses", &format_args!("<{} rounds redacted>", self.responses.len()))
+            .finish()
+    }
+}
+
+/// The SSH_MSG_USERAUTH_BANNER for `user_name`, if the handler has a banner. Line breaks
+/// are sent as CRLF, as RFC 4252 §5.4 asks.
+pub async fn banner_for<H: AuthHandler>(handler: &H, user_name: &str) -> Option<MsgUserauthBanner> {
+    let text = handler.banner(user_name).await.filter(|text| !text.is_empty())?;
+    let message = text.replace("\r\n", "\n").replace('\n', "\r\n");
+    Some(MsgUserauthBanner { message, language_tag: String::new() })
+}
+
+/// Puts one userauth request to the handler. Methods the handler does not offer the user
+/// fail without consulting it.
+pub async fn handle_request<H: AuthHandler>(
+    handler: &H,
+    session_id: &SessionId,
+    header: &MsgUserauthRequest,
+    request: &AuthRequest,
+) -> AuthReply {
+    let user_name = header.user_name.as_str();
+    let methods = handler.methods(user_name);
+    let offered = matches!(request, AuthRequest::None) || methods.contains(&request.method_name());
+    let result = if !offered {
+        AuthResult::Reject
+    } else {
+        match request {
+            AuthRequest::None => handler.auth_none(user_name).await,
+            AuthRequest::Password { password, new_password } => {
+                handler.auth_password(user_name, password, new_password.as_deref()).await
+            }
+            AuthRequest::PublicKey { algorithm, key_blob, signature } => {
+                let policy = handler.signature_policy();
+                match check_key_algorithm(algorithm, key_blob, &policy) {
+                    Err(_) => AuthResult::Reject,
+                    Ok(key) => match signature {
+                        None if handler.publickey_acceptable(user_name, &key).await => {
+                            return AuthReply::PkOk(MsgUserauthPkOk {
+                                algorithm_name: algorithm.clone(),
+                                public_key_blob: key_blob.clone(),
+                            });
+                        }
+                        None => AuthResult::Reject,
+                        Some(signature) => {
+                            let data = publickey_signature_data(
+                                session_id,
+                                user_name,
+                                &header.service_name,
+                                algorithm,
+                                key_blob,
+                            );
+                            match verify_request_signature(&key, algorithm, &data, signature, &policy) {
+                                Ok(()) => handler.auth_publickey(user_name, &key).await,
+                                Err(_) => AuthResult::Reject,
+                            }
+                        }
+                    },
+                }
+            }
+            AuthRequest::KeyboardInteractive { submethods } => {
+                handler.auth_keyboard_interactive(user_name, submethods).await
+            }
+            AuthRequest::Hostbased { algorithm, host_key_blob, client_host_name, client_user_name, signature } => {
+                let policy = handler.signature_policy();
+                match check_key_algorithm(algorithm, host_key_blob, &policy) {
+                    Err(_) => AuthResult::Reject,
+                    Ok(host_key) => {
+                        let data = hostbased_signature_data(
+                            session_id,
+                            user_name,
+                            &header.service_name,
+                            algorithm,
+                            host_key_blob,
+                            client_host_name,
+                            client_user_name,
+                        );
+                        match verify_request_signature(&host_key, algorithm, &data, signature, &policy) {
+                            Ok(()) => {
+                                handler.auth_hostbased(user_name, client_host_name, client_user_name, &host_key).await
+                            }
+                            Err(_) => AuthResult::Reject,
+                        }
+                    }
+                }
+            }
+            AuthRequest::Unsupported(_) => AuthResult::Reject,
+        }
+    };
+
+    let exchange = InfoExchange {
+        user_name: header.user_name.clone(),
+        service_name: header.service_name.clone(),
+        prompts: 0,
+        responses: Vec::new(),
+    };
+    // Asked again, as a partial success changes what is left to do.
+    reply(&handler.methods(user_name), request.method_name(), result, exchange)
+}
+
+/// Puts the client's answers to a keyboard-interactive round to the handler. A response
+/// with the wrong number of answers fails the exchange.
+pub async fn handle_info_response<H: AuthHandler>(
+    handler: &H,
+    mut exchange: InfoExchange,
+    response: &MsgUserauthInfoResponse,
+) -> AuthReply {
+    let result = if response.responses.len() != exchange.prompts {
+        AuthResult::Reject
+    } else {
+        exchange.responses.push(response.responses.clone());
+        handler.auth_info_response(&exchange.user_name, &exchange.responses).await
+    };
+    let methods = handler.methods(&exchange.user_name);
+    reply(&methods, "keyboard-interactive", result, exchange)
+}
+
+/// The reply to a request of `method_name` the handler decided on.
+fn reply(methods: &[&'static str], method_name: &str, result: AuthResult, exchange: InfoExchange) -> AuthReply {
+    let failure = |partial_success| {
+        AuthReply::Failure(MsgUserauthFailure {
+            authentications_that_can_continue: methods.iter().map(|method| method.to_string()).collect(),
+            partial_success,
+        })
+    };
+    match result {
+        AuthResult::Accept => AuthReply::Success(Restrictions::default()),
+        AuthResult::Restricted(restrictions) => AuthReply::Success(restrictions),
+        AuthResult::Reject => failure(false),
+        AuthResult::PartialSuccess => failure(true),
+        AuthResult::ChangePassword { prompt } if method_name == "password" => {
+            AuthReply::PasswdChangeReq(MsgUserauthPasswdChangeReq { prompt, language_tag: String::new() })
+        }
+        AuthResult::InfoRequest { name, instruction, prompts } if method_name == "keyboard-interactive" => {
+            let exchange = InfoExchange { prompts: prompts.len(), ..exchange };
+            AuthReply::InfoRequest(MsgUserauthInfoRequest { name, instruction, language_tag: String::new(), prompts }, exchange)
+        }
+        AuthResult::ChangePassword { .. } | AuthResult::InfoRequest { .. } => failure(false),
+    }
+}
+
+/// Client side: answers one keyboard-interactive round with `prompt`, which is given the
+/// request and returns one answer per prompt. Answers of the wrong count are an error,
+/// as the server would reject them.
+pub fn answer_info_request(
+    request: &MsgUserauthInfoRequest,
+    prompt: &mut impl FnMut(&MsgUserauthInfoRequest) -> Vec<String>,
+) -> Result<MsgUserauthInfoResponse, std::io::Error> {
+    let responses = prompt(request);
+    if responses.len() != request.prompts.len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} answers to {} prompts", responses.len(), request.prompts.len()),
+        ));
+    }
+    Ok(MsgUserauthInfoResponse { responses })
+}
+
+/// Client side: the answer to SSH_MSG_USERAUTH_PASSWD_CHANGEREQ, which repeats the old
+/// password alongside the new one (RFC 4252 §8).
+pub fn password_change_request(old_password: &str, new_password: &str) -> AuthRequest {
+    AuthRequest::Password { password: old_password.to_string(), new_password: Some(new_password.to_string()) }
+}
+
+/// Client side: a banner made safe to print on a terminal. Control characters other than
+/// line breaks and tabs are dropped, so a server cannot send escape sequences
+/// (RFC 4252 §5.4), and CRLF becomes a plain line break.
+pub fn sanitize_banner(message: &str) -> String {
+    message.chars().filter(|&c| !c.is_control() || c == '\n' || c == '\t').collect()
+}
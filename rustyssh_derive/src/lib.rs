@@ -18,7 +18,27 @@ fn has_discriminants(
     has_discriminant
 }
 
-#[proc_macro_derive(ReadSSH)]
+/// The SSH name of a variant of a name-based enum: `#[ssh(name = "...")]` if given, which is
+/// needed for names that are not valid identifiers (e.g. containing `@` or `.`), otherwise the
+/// identifier with `__` replaced by `-`.
+fn ssh_name(variant: &syn::Variant) -> String {
+    let mut name = None;
+    for attr in variant.attrs.iter().filter(|attr| attr.path().is_ident("ssh")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                let value: syn::LitStr = meta.value()?.parse()?;
+                name = Some(value.value());
+                Ok(())
+            } else {
+                Err(meta.error("unsupported ssh attribute"))
+            }
+        })
+        .expect("invalid #[ssh(...)] attribute");
+    }
+    name.unwrap_or_else(|| variant.ident.to_string().replace("__", "-"))
+}
+
+#[proc_macro_derive(ReadSSH, attributes(ssh))]
 pub fn derive_read_ssh(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     let struct_name = input.ident;
@@ -89,7 +109,7 @@ pub fn derive_read_ssh(input: TokenStream) -> TokenStream {
             let variant_matches = data.variants.iter().map(|v| {
                 let variant = &v.ident;
                 // Apply the same transformation as in WriteSSH for consistent matching
-                let variant_str = variant.to_string().replace("__", "-"); 
+                let variant_str = ssh_name(v);
 
                 if !variant.to_string().contains("Unknown") { // Check original ident string for "Unknown"
                     quote! {
@@ -120,7 +140,7 @@ pub fn derive_read_ssh(input: TokenStream) -> TokenStream {
     TokenStream::from(expanded)
 }
 
-#[proc_macro_derive(WriteSSH)]
+#[proc_macro_derive(WriteSSH, attributes(ssh))]
 pub fn derive_write_ssh(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     let struct_name = input.ident;
@@ -223,7 +243,7 @@ pub fn derive_write_ssh(input: TokenStream) -> TokenStream {
                         }
                     }
                 } else {
-                    let variant_name_literal = variant_name_str.replace("__", "-");
+                    let variant_name_literal = ssh_name(v);
                     quote! {
                         #struct_name::#variant_ident => #variant_name_literal.to_string().write_ssh(writer),
                     }